@@ -0,0 +1,30 @@
+use pb_rs::{types::FileDescriptor, ConfigBuilder};
+use std::path::{Path, PathBuf};
+
+// Generate quick-protobuf bindings for every `.proto` file under `proto/` into
+// `$OUT_DIR/generated`, which is included by `src/proto.rs`.
+fn main() {
+    let in_dir = Path::new("proto");
+    let out_dir = PathBuf::from(std::env::var("OUT_DIR").unwrap()).join("generated");
+
+    println!("cargo:rerun-if-changed={}", in_dir.display());
+    let mut protos = Vec::new();
+    for entry in std::fs::read_dir(in_dir).expect("proto directory should exist") {
+        let path = entry.unwrap().path();
+        if path.extension().is_some_and(|ext| ext == "proto") {
+            println!("cargo:rerun-if-changed={}", path.display());
+            protos.push(path);
+        }
+    }
+
+    // Start from a clean output directory so removed messages do not linger
+    if out_dir.exists() {
+        std::fs::remove_dir_all(&out_dir).unwrap();
+    }
+    std::fs::create_dir_all(&out_dir).unwrap();
+
+    let config_builder = ConfigBuilder::new(&protos, None, Some(&out_dir), &[in_dir.to_path_buf()])
+        .expect("proto files should be valid")
+        .dont_use_cow(true);
+    FileDescriptor::run(&config_builder.build()).expect("bindings should generate");
+}
@@ -0,0 +1,88 @@
+//! A behaviour remembering addresses of peers, so that dialing a peer by id (e.g. when opening
+//! a proxy stream) can use addresses learned outside of rendezvous discovery.
+
+use libp2p::{
+    core::{transport::PortUse, Endpoint},
+    swarm::{
+        dummy, ConnectionDenied, ConnectionId, FromSwarm, NetworkBehaviour, THandler,
+        THandlerInEvent, THandlerOutEvent, ToSwarm,
+    },
+    Multiaddr, PeerId,
+};
+use std::collections::{HashMap, HashSet};
+use std::convert::Infallible;
+use std::task::{Context, Poll};
+
+#[derive(Default)]
+pub(crate) struct Behaviour {
+    addresses: HashMap<PeerId, HashSet<Multiaddr>>,
+}
+
+impl Behaviour {
+    pub(crate) fn add_address(&mut self, peer_id: PeerId, address: Multiaddr) {
+        self.addresses.entry(peer_id).or_default().insert(address);
+    }
+
+    pub(crate) fn remove_address(&mut self, peer_id: &PeerId, address: &Multiaddr) {
+        if let Some(addresses) = self.addresses.get_mut(peer_id) {
+            addresses.remove(address);
+            if addresses.is_empty() {
+                self.addresses.remove(peer_id);
+            }
+        }
+    }
+}
+
+impl NetworkBehaviour for Behaviour {
+    type ConnectionHandler = dummy::ConnectionHandler;
+    type ToSwarm = Infallible;
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        _connection_id: ConnectionId,
+        _peer: PeerId,
+        _local_addr: &Multiaddr,
+        _remote_addr: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        Ok(dummy::ConnectionHandler)
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        _connection_id: ConnectionId,
+        _peer: PeerId,
+        _addr: &Multiaddr,
+        _role_override: Endpoint,
+        _port_use: PortUse,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        Ok(dummy::ConnectionHandler)
+    }
+
+    fn handle_pending_outbound_connection(
+        &mut self,
+        _connection_id: ConnectionId,
+        maybe_peer: Option<PeerId>,
+        _addresses: &[Multiaddr],
+        _effective_role: Endpoint,
+    ) -> Result<Vec<Multiaddr>, ConnectionDenied> {
+        Ok(maybe_peer
+            .and_then(|peer_id| self.addresses.get(&peer_id))
+            .map(|addresses| addresses.iter().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    fn on_swarm_event(&mut self, _event: FromSwarm) {}
+
+    fn on_connection_handler_event(
+        &mut self,
+        _peer_id: PeerId,
+        _connection_id: ConnectionId,
+        event: THandlerOutEvent<Self>,
+    ) {
+        match event {}
+    }
+
+    fn poll(&mut self, _cx: &mut Context<'_>) -> Poll<ToSwarm<Self::ToSwarm, THandlerInEvent<Self>>> {
+        Poll::Pending
+    }
+}
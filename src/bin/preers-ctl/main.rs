@@ -1,10 +1,9 @@
 use clap::{Parser, Subcommand, ValueEnum};
 
-use preers::data::{ProvideService, UseService, Rendezvous, NetworkInfo};
+use preers::client::Client;
+use libp2p::PeerId;
+use preers::data::{Contact, PeerAddress, ProvideService, UseService};
 use preers::DEFAULT_HTTP_PORT;
-use serde::de::DeserializeOwned;
-use serde::Serialize;
-use reqwest::Url;
 
 #[derive(Parser)]
 #[command(name = "preers-ctl")]
@@ -20,12 +19,35 @@ struct Cli {
 enum Target {
     Rendezvous,
     Use,
-    Provide
+    Provide,
+    Address,
+    Contact,
+    Namespace,
 }
 
 #[derive(Subcommand)]
 enum Commands {
     Info,
+    /// Print events of the node as they happen
+    Watch,
+    /// Attempt a direct connection with a peer connected through a relay
+    Direct {
+        /// PeerId or the alias of a contact
+        peer_id: String,
+    },
+    /// Shut the node down, letting proxied sessions finish first
+    Shutdown,
+    /// Reload the config file, log filter and services of the node
+    Reload,
+    /// Print the log filter of the node, or change it until the next reload
+    Log {
+        /// Filter directives, e.g. info,preers=debug
+        directives: Option<String>,
+    },
+    /// Print the sessions the node is proxying
+    Sessions,
+    /// Print the metrics of the node in the OpenMetrics text format
+    Metrics,
     List {
         target: Target,
     },
@@ -34,9 +56,20 @@ enum Commands {
 
         multiaddr: Option<String>,
 
+        /// PeerId or the alias of a contact
         #[arg(long)]
         peer_id: Option<String>,
-        
+
+        #[arg(long)]
+        alias: Option<String>,
+
+        #[arg(long)]
+        note: Option<String>,
+
+        /// Rendezvous namespace
+        #[arg(long)]
+        name: Option<String>,
+
         #[arg(short = 'H', long)]
         host: Option<String>,
 
@@ -52,76 +85,93 @@ enum Commands {
     }
 }
 
-fn target_to_url(target: Target, port: u16) -> String {
+async fn list_cmd(client: &Client, target: Target) -> Result<(), Box<dyn std::error::Error>> {
     match target {
-        Target::Rendezvous => format!("http://localhost:{port}/rendezvous"),
-        Target::Use => format!("http://localhost:{port}/use_service"),
-        Target::Provide => format!("http://localhost:{port}/provide_service")
+        Target::Rendezvous => println!("{:#?}", client.rendezvous().await?),
+        Target::Use => println!("{:#?}", client.used_services().await?),
+        Target::Provide => println!("{:#?}", client.provided_services().await?),
+        Target::Address => println!("{:#?}", client.address_book().await?),
+        Target::Contact => println!("{:#?}", client.contacts().await?),
+        Target::Namespace => println!("{:#?}", client.namespaces().await?),
     }
+    Ok(())
 }
 
-async fn list_cmd<T: DeserializeOwned + std::fmt::Debug>(target: Target) -> Result<(), Box<dyn std::error::Error>> {
-    let resp = reqwest::get(target_to_url(target, DEFAULT_HTTP_PORT))
-        .await?
-        .json::<T>()
-        .await?;
+async fn info_cmd(client: &Client) -> Result<(), Box<dyn std::error::Error>> {
+    let resp = client.network_info().await?;
     println!("{resp:#?}");
     Ok(())
 }
 
-async fn add_cmd<T: Serialize>(target: Target, object: T) -> Result<(), Box<dyn std::error::Error>> {
-    let client = reqwest::Client::new();
-    let resp = client.post(target_to_url(target, DEFAULT_HTTP_PORT))
-        .json(&object)
-        .send()
-        .await?;
-    println!("{resp:#?}");
+async fn watch_cmd(client: &Client) -> Result<(), Box<dyn std::error::Error>> {
+    let mut events = client.events().await?;
+    while let Some(event) = events.next().await? {
+        println!("{event:?}");
+    }
     Ok(())
 }
 
-async fn info_cmd() -> Result<(), Box<dyn std::error::Error>> {
-    let resp = reqwest::get(format!("http://localhost:{}/network_info", DEFAULT_HTTP_PORT))
-        .await?
-        .json::<NetworkInfo>()
-        .await?;
-    println!("{resp:#?}");
+async fn del_cmd(client: &Client, target: Target, id: i64) -> Result<(), Box<dyn std::error::Error>> {
+    match target {
+        Target::Rendezvous => client.del_rendezvous(id).await?,
+        Target::Use => client.del_used_service(id).await?,
+        Target::Provide => client.del_provided_service(id).await?,
+        Target::Address => client.del_peer_address(id).await?,
+        Target::Contact => client.del_contact(id).await?,
+        Target::Namespace => client.del_namespace(id).await?,
+    }
     Ok(())
 }
 
-async fn del_cmd(target: Target, id: i64) -> Result<(), Box<dyn std::error::Error>> {
-    let client = reqwest::Client::new();
-    let params = [("id", id.to_string())];
-    let url_with_params = Url::parse_with_params(target_to_url(target, DEFAULT_HTTP_PORT).as_str(), &params)?;
-    let resp = client.delete(url_with_params).send().await?;
-    println!("{resp:#?}");
-    Ok(())
+// Accept either a PeerId or the alias of a contact
+async fn resolve_peer_id(client: &Client, peer_id: &str) -> Result<PeerId, Box<dyn std::error::Error>> {
+    if let Ok(peer_id) = peer_id.parse() {
+        return Ok(peer_id);
+    }
+    client
+        .contacts()
+        .await?
+        .into_iter()
+        .find(|contact| contact.alias == peer_id)
+        .map(|contact| contact.peer_id)
+        .ok_or_else(|| format!("unknown peer id or alias: {peer_id}").into())
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let cli = Cli::parse();
+    let client = Client::new(cli.port.unwrap_or(DEFAULT_HTTP_PORT));
     match cli.command {
-        Commands::Info => info_cmd().await?,
-        Commands::List { target } => {
-            match target {
-                Target::Rendezvous =>  list_cmd::<Vec<Rendezvous>>(target).await?,
-                Target::Use =>  list_cmd::<Vec<UseService>>(target).await?,
-                Target::Provide =>  list_cmd::<Vec<ProvideService>>(target).await?,
-            }
+        Commands::Info => info_cmd(&client).await?,
+        Commands::Watch => watch_cmd(&client).await?,
+        Commands::Direct { peer_id } => {
+            client.connect_direct(resolve_peer_id(&client, &peer_id).await?).await?;
         }
-        Commands::Add { target, multiaddr, peer_id, host, port, forwarder_port } => {
+        Commands::Shutdown => client.shutdown().await?,
+        Commands::Log { directives: Some(directives) } => client.set_log_level(directives).await?,
+        Commands::Log { directives: None } => println!("{}", client.log_level().await?.directives),
+        Commands::Sessions => println!("{:#?}", client.sessions().await?),
+        Commands::Metrics => print!("{}", client.metrics().await?),
+        Commands::Reload => {
+            let resp = client.reload().await?;
+            println!("{resp:#?}");
+        }
+        Commands::List { target } => list_cmd(&client, target).await?,
+        Commands::Add { target, multiaddr, peer_id, alias, note, name, host, port, forwarder_port } => {
             match target {
                 Target::Rendezvous => {
                     if let Some(multiaddr) = multiaddr {
-                        add_cmd(target, Rendezvous { id: 0, multiaddr: multiaddr.parse()? }).await?;
+                        let resp = client.add_rendezvous(multiaddr.parse()?).await?;
+                        println!("{resp:#?}");
                     } else {
                         eprintln!("must provide multiaddr");
                     }
                 }
                 Target::Provide => {
                     if let (Some(host), Some(port)) = (host, port) {
-                        add_cmd(target, ProvideService { id: 0, host, port }).await?;
+                        let resp = client.add_provided_service(&ProvideService { id: 0, host, port }).await?;
+                        println!("{resp:#?}");
                     } else {
                         eprintln!("must provide host and port");
                     }
@@ -129,15 +179,40 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 Target::Use => {
                     if let (Some(peer_id), Some(host), Some(port), Some(forwarder_port)) = 
                         (peer_id, host, port, forwarder_port) {
-                            add_cmd(target, UseService { id: 0, peer_id: peer_id.parse()?, host, port, forwarder_port }).await?;
+                            let resp = client.add_used_service(&UseService { id: 0, peer_id: resolve_peer_id(&client, &peer_id).await?, host, port, forwarder_port }).await?;
+                            println!("{resp:#?}");
                     } else {
                         eprintln!("must provide peer_id, host, port, and forwarder_port")
                     }
                 }
+                Target::Address => {
+                    if let (Some(peer_id), Some(multiaddr)) = (peer_id, multiaddr) {
+                        let resp = client.add_peer_address(&PeerAddress { id: 0, peer_id: resolve_peer_id(&client, &peer_id).await?, multiaddr: multiaddr.parse()? }).await?;
+                        println!("{resp:#?}");
+                    } else {
+                        eprintln!("must provide peer_id and multiaddr");
+                    }
+                }
+                Target::Contact => {
+                    if let (Some(alias), Some(peer_id)) = (alias, peer_id) {
+                        let resp = client.add_contact(&Contact { id: 0, alias, peer_id: peer_id.parse()?, note: note.unwrap_or_default() }).await?;
+                        println!("{resp:#?}");
+                    } else {
+                        eprintln!("must provide alias and peer_id");
+                    }
+                }
+                Target::Namespace => {
+                    if let Some(name) = name {
+                        let resp = client.add_namespace(name).await?;
+                        println!("{resp:#?}");
+                    } else {
+                        eprintln!("must provide name");
+                    }
+                }
             }
         }
         Commands::Del { target, id } => {
-            del_cmd(target, id).await?;
+            del_cmd(&client, target, id).await?;
         }
     }
     Ok(())
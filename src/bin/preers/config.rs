@@ -0,0 +1,170 @@
+use clap::{error::ErrorKind, CommandFactory, Parser};
+use std::error::Error;
+use std::ffi::OsString;
+use std::path::Path;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, oneshot};
+use tracing_subscriber::{filter::LevelFilter, reload, EnvFilter, Registry};
+
+use preers::data::Reloaded;
+use preers::{Command, Reload, Responder};
+
+use crate::{db, transport_config, Cli, DEFAULT_DB_PATH, DEFAULT_LOG_FILE};
+
+/// Swaps the log filter of the running node.
+pub type LogHandle = reload::Handle<EnvFilter, Registry>;
+
+/// Requests to reload, answered with the outcome or why the settings could not be read.
+pub type ReloadRequest = Responder<Result<Reloaded, String>>;
+
+/// Parse the command line, preceded by the flags of the `--config` file if any.
+/// Paths are then taken from `--data-dir` if given.
+pub fn parse_cli() -> Result<Cli, clap::Error> {
+    let cli = Cli::try_parse()?;
+    let mut cli = match &cli.config {
+        Some(path) => {
+            let config_args = config_args(path)
+                .map_err(|error| Cli::command().error(ErrorKind::InvalidValue, error))?;
+            let mut args = std::env::args_os();
+            let program = args.next().unwrap_or_else(|| "preers".into());
+            Cli::try_parse_from(
+                std::iter::once(program)
+                    .chain(config_args)
+                    .chain(args),
+            )?
+        }
+        None => cli,
+    };
+    if let Some(data_dir) = cli.data_dir.clone() {
+        in_data_dir(&mut cli, &data_dir);
+    }
+    cli.logging.log_file.take_if(|path| path.as_os_str() == "-");
+    Ok(cli)
+}
+
+// Make the paths of the flags relative to the data directory, absolute ones are kept. Logs are
+// written there too unless `--log-file -` asks for stdout.
+fn in_data_dir(cli: &mut Cli, data_dir: &Path) {
+    cli.db = Some(data_dir.join(cli.db.take().unwrap_or(DEFAULT_DB_PATH.into())));
+    cli.logging.log_file.get_or_insert(DEFAULT_LOG_FILE.into());
+    for path in [
+        &mut cli.pidfile,
+        &mut cli.logging.log_file,
+        &mut cli.transport.swarm_key,
+        &mut cli.transport.ws_tls_cert,
+        &mut cli.transport.ws_tls_key,
+    ]
+    .into_iter()
+    .flatten()
+    .filter(|path| path.as_os_str() != "-")
+    {
+        *path = data_dir.join(&*path);
+    }
+}
+
+// Command line flags of a config file, whose keys are long flag names, e.g. `relay = true` for
+// `--relay` or `listen = ["/ip4/0.0.0.0/tcp/4001"]` for `--listen`
+fn config_args(path: &Path) -> Result<Vec<OsString>, String> {
+    let config = std::fs::read_to_string(path)
+        .map_err(|error| format!("failed to read config {}: {error}", path.display()))?;
+    let table = config
+        .parse::<toml::Table>()
+        .map_err(|error| format!("invalid config {}: {error}", path.display()))?;
+    let mut args = Vec::new();
+    for (key, value) in table {
+        let values = match value {
+            toml::Value::Array(values) => values,
+            value => vec![value],
+        };
+        for value in values {
+            match value {
+                toml::Value::Boolean(true) => args.push(format!("--{key}").into()),
+                toml::Value::Boolean(false) => {}
+                toml::Value::String(value) => args.push(format!("--{key}={value}").into()),
+                toml::Value::Integer(value) => args.push(format!("--{key}={value}").into()),
+                value => {
+                    return Err(format!(
+                        "unsupported value of {key} in config {}: {value}",
+                        path.display()
+                    ))
+                }
+            }
+        }
+    }
+    Ok(args)
+}
+
+/// Log filter of `--log`, or of the `RUST_LOG` environment variable if not given.
+pub fn log_filter(log: Option<&str>) -> Result<EnvFilter, String> {
+    match log {
+        Some(log) => EnvFilter::try_new(log).map_err(|error| format!("invalid log filter: {error}")),
+        None => Ok(EnvFilter::builder()
+            .with_default_directive(LevelFilter::INFO.into())
+            .from_env_lossy()),
+    }
+}
+
+/// Re-reads the settings from the command line, the `--config` file and the database, and
+/// applies them to the running node.
+pub struct Reloader {
+    pub log: LogHandle,
+    pub db_tx: mpsc::Sender<db::Command>,
+    pub app_tx: mpsc::Sender<Command>,
+}
+
+impl Reloader {
+    async fn reload(&self) -> Result<Reloaded, Box<dyn Error>> {
+        // read everything first, so that nothing is applied if any of it is invalid
+        let cli = parse_cli()?;
+        let filter = log_filter(cli.logging.log.as_deref())?;
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.db_tx
+            .send(db::Command::GetUsedServices(resp_tx))
+            .await?;
+        let used_services = resp_rx.await??;
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.db_tx
+            .send(db::Command::GetProvidedServices(resp_tx))
+            .await?;
+        let provided_services = resp_rx.await??;
+        let reload = Reload {
+            transport: transport_config(cli.transport, cli.port)?,
+            relay: cli.relay.then(|| cli.relay_limits.into()),
+            max_relays: cli.max_relays,
+            direct_wait: Duration::from_secs(cli.direct_wait),
+            close_relayed: cli.close_relayed,
+            drain_timeout: Duration::from_secs(cli.drain_timeout),
+            used_services,
+            provided_services,
+        };
+        self.log.reload(filter)?;
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.app_tx
+            .send(Command::Reload(Box::new(reload), resp_tx))
+            .await?;
+        Ok(resp_rx.await?)
+    }
+
+    /// Reload on SIGHUP and on requests received on `rx`.
+    pub async fn run(self, mut rx: mpsc::Receiver<ReloadRequest>) {
+        let mut hangup = signal(SignalKind::hangup()).expect("should be able to handle SIGHUP");
+        loop {
+            let result = tokio::select! {
+                Some(_) = hangup.recv() => {
+                    tracing::info!("received SIGHUP, reloading");
+                    self.reload().await
+                }
+                Some(resp) = rx.recv() => {
+                    let result = self.reload().await;
+                    let _ = resp.send(result.as_ref().cloned().map_err(|error| error.to_string()));
+                    result
+                }
+                else => break,
+            };
+            if let Err(error) = result {
+                tracing::error!(%error, "reload error");
+            }
+        }
+    }
+}
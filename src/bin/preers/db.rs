@@ -1,14 +1,17 @@
 use libp2p::{multiaddr::Multiaddr, PeerId};
-use rusqlite::{Connection, Result};
+use rusqlite::{Connection, OptionalExtension, Result};
 use std::path::Path;
 use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::sync::mpsc;
-use tracing;
-use preers::data::{ProvideService, Rendezvous, UseService};
+use preers::data::{
+    Contact, KnownPeer, Namespace, PeerAddress, ProvideService, Rendezvous, UseService,
+};
 
 pub use rusqlite::Error;
 
-use crate::Responder;
+use preers::network::PEER_EXPIRY;
+use preers::Responder;
 
 #[derive(Debug)]
 pub(crate) enum Command {
@@ -23,6 +26,13 @@ pub(crate) enum Command {
     GetRendezvous(Responder<Result<Vec<Rendezvous>>>),
     GetUsedServices(Responder<Result<Vec<UseService>>>),
     GetProvidedServices(Responder<Result<Vec<ProvideService>>>),
+    GetAddressBook(Responder<Result<Vec<PeerAddress>>>),
+    GetContacts(Responder<Result<Vec<Contact>>>),
+    ResolveAlias(String, Responder<Result<Option<PeerId>>>),
+    GetNamespaces(Responder<Result<Vec<Namespace>>>),
+    SavePeer(KnownPeer),
+    /// Stop the worker and close the database
+    Close,
 }
 
 #[derive(Debug)]
@@ -30,6 +40,9 @@ pub(crate) enum AddInner {
     Rendezvous(Multiaddr),
     ProvideService(ProvideService),
     UseService(UseService),
+    PeerAddress(PeerAddress),
+    Contact(Contact),
+    Namespace(String),
 }
 
 #[derive(Debug)]
@@ -37,6 +50,9 @@ pub(crate) enum DelInner {
     Rendezvous(i64),
     ProvideService(i64),
     UseService(i64),
+    PeerAddress(i64),
+    Contact(i64),
+    Namespace(i64),
 }
 
 #[derive(Debug)]
@@ -71,6 +87,29 @@ impl DataBase {
         Ok(())
     }
 
+    // Tables added after the initial schema, safe to run on every start
+    pub fn migrate(&mut self) -> Result<()> {
+        self.conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS peers (
+                peer_id     TEXT PRIMARY KEY,
+                addresses   TEXT NOT NULL,
+                first_seen  INTEGER NOT NULL,
+                last_seen   INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS address_book (
+                peer_id     TEXT NOT NULL,
+                multiaddr   TEXT NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS contacts (
+                alias       TEXT PRIMARY KEY,
+                peer_id     TEXT NOT NULL,
+                note        TEXT NOT NULL DEFAULT('')
+            );
+            CREATE TABLE IF NOT EXISTS namespaces ( name TEXT PRIMARY KEY );",
+        )?;
+        Ok(())
+    }
+
     pub fn set_setting(&mut self, key: &str, value: &[u8]) -> Result<()> {
         self.conn.execute(
             "INSERT INTO settings (key, value) 
@@ -180,6 +219,172 @@ impl DataBase {
         Ok(self.conn.last_insert_rowid())
     }
 
+    pub fn get_address_book(&mut self) -> Result<Vec<PeerAddress>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT rowid, peer_id, multiaddr FROM address_book")?;
+        let address_book = stmt.query_map([], |row| {
+            Ok(PeerAddress {
+                id: row.get(0)?,
+                peer_id: PeerId::from_str(&row.get::<usize, String>(1)?).expect("should parse"),
+                multiaddr: Multiaddr::from_str(&row.get::<usize, String>(2)?)
+                    .expect("should parse"),
+            })
+        })?;
+        let mut v = Vec::new();
+        for get_result in address_book {
+            match get_result {
+                Ok(peer_address) => v.push(peer_address),
+                Err(error) => tracing::error!(?error, "getting address_book row error"),
+            }
+        }
+        Ok(v)
+    }
+
+    pub fn add_peer_address(&mut self, peer_address: &PeerAddress) -> Result<i64> {
+        self.conn.execute(
+            "INSERT INTO address_book (peer_id, multiaddr) VALUES (?1, ?2)",
+            (
+                peer_address.peer_id.to_base58(),
+                peer_address.multiaddr.to_string(),
+            ),
+        )?;
+        Ok(self.conn.last_insert_rowid())
+    }
+
+    pub fn get_contacts(&mut self) -> Result<Vec<Contact>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT rowid, alias, peer_id, note FROM contacts")?;
+        let contacts = stmt.query_map([], |row| {
+            Ok(Contact {
+                id: row.get(0)?,
+                alias: row.get(1)?,
+                peer_id: PeerId::from_str(&row.get::<usize, String>(2)?).expect("should parse"),
+                note: row.get(3)?,
+            })
+        })?;
+        let mut v = Vec::new();
+        for get_result in contacts {
+            match get_result {
+                Ok(contact) => v.push(contact),
+                Err(error) => tracing::error!(?error, "getting contacts row error"),
+            }
+        }
+        Ok(v)
+    }
+
+    pub fn add_contact(&mut self, contact: &Contact) -> Result<i64> {
+        self.conn.execute(
+            "INSERT INTO contacts (alias, peer_id, note) VALUES (?1, ?2, ?3)",
+            (&contact.alias, contact.peer_id.to_base58(), &contact.note),
+        )?;
+        Ok(self.conn.last_insert_rowid())
+    }
+
+    pub fn resolve_alias(&mut self, alias: &str) -> Result<Option<PeerId>> {
+        let peer_id = self
+            .conn
+            .query_row(
+                "SELECT peer_id FROM contacts WHERE alias = ?1",
+                [alias],
+                |row| row.get::<usize, String>(0),
+            )
+            .optional()?;
+        Ok(peer_id.map(|peer_id| PeerId::from_str(&peer_id).expect("should parse")))
+    }
+
+    pub fn get_namespaces(&mut self) -> Result<Vec<Namespace>> {
+        let mut stmt = self.conn.prepare("SELECT rowid, name FROM namespaces")?;
+        let namespaces = stmt.query_map([], |row| {
+            Ok(Namespace {
+                id: row.get(0)?,
+                name: row.get(1)?,
+            })
+        })?;
+        let mut v = Vec::new();
+        for get_result in namespaces {
+            match get_result {
+                Ok(namespace) => v.push(namespace),
+                Err(error) => tracing::error!(?error, "getting namespaces row error"),
+            }
+        }
+        Ok(v)
+    }
+
+    pub fn add_namespace(&mut self, name: &str) -> Result<i64> {
+        self.conn
+            .execute("INSERT INTO namespaces (name) VALUES (?1)", [name])?;
+        Ok(self.conn.last_insert_rowid())
+    }
+
+    /// Peers seen within [`PEER_EXPIRY`], older ones are deleted.
+    pub fn get_peers(&mut self) -> Result<Vec<KnownPeer>> {
+        self.prune_peers()?;
+        let mut stmt = self
+            .conn
+            .prepare("SELECT peer_id, addresses, first_seen, last_seen FROM peers")?;
+        let peers = stmt.query_map([], |row| {
+            let addresses: Vec<String> =
+                serde_json::from_str(&row.get::<usize, String>(1)?).unwrap_or_default();
+            Ok(KnownPeer {
+                peer_id: PeerId::from_str(&row.get::<usize, String>(0)?).expect("should parse"),
+                addresses: addresses
+                    .iter()
+                    .filter_map(|address| Multiaddr::from_str(address).ok())
+                    .collect(),
+                first_seen: row.get(2)?,
+                last_seen: row.get(3)?,
+            })
+        })?;
+        let mut v = Vec::new();
+        for get_result in peers {
+            match get_result {
+                Ok(peer) => v.push(peer),
+                Err(error) => tracing::error!(?error, "getting peers row error"),
+            }
+        }
+        Ok(v)
+    }
+
+    // Forget peers not seen in a long time like the network does, their addresses are likely
+    // stale
+    fn prune_peers(&mut self) -> Result<()> {
+        let expired_before = SystemTime::now()
+            .checked_sub(PEER_EXPIRY)
+            .and_then(|at| at.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |at| at.as_secs());
+        let deleted = self
+            .conn
+            .execute("DELETE FROM peers WHERE last_seen < ?1", [expired_before])?;
+        if deleted > 0 {
+            tracing::debug!(deleted, "forgot expired peers");
+        }
+        Ok(())
+    }
+
+    pub fn save_peer(&mut self, peer: &KnownPeer) -> Result<()> {
+        let addresses = peer
+            .addresses
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<String>>();
+        self.conn.execute(
+            "INSERT INTO peers (peer_id, addresses, first_seen, last_seen)
+                VALUES (?1, ?2, ?3, ?4)
+                ON CONFLICT(peer_id) DO UPDATE SET
+                    addresses = excluded.addresses,
+                    last_seen = excluded.last_seen",
+            (
+                peer.peer_id.to_base58(),
+                serde_json::to_string(&addresses).expect("should serialize"),
+                peer.first_seen,
+                peer.last_seen,
+            ),
+        )?;
+        Ok(())
+    }
+
     pub fn handle_add(&mut self, cmd: AddInner) -> Result<i64> {
         match cmd {
             AddInner::Rendezvous(multiaddr) => {
@@ -193,6 +398,9 @@ impl DataBase {
             AddInner::ProvideService(provide_service) => {
                 self.add_provided_service(&provide_service)
             }
+            AddInner::PeerAddress(peer_address) => self.add_peer_address(&peer_address),
+            AddInner::Contact(contact) => self.add_contact(&contact),
+            AddInner::Namespace(name) => self.add_namespace(&name),
         }
     }
 
@@ -211,37 +419,104 @@ impl DataBase {
             DelInner::Rendezvous(id) => self.delete_with_id("rendezvous", id),
             DelInner::UseService(id) => self.delete_with_id("used_services", id),
             DelInner::ProvideService(id) => self.delete_with_id("provided_services", id),
+            DelInner::PeerAddress(id) => self.delete_with_id("address_book", id),
+            DelInner::Contact(id) => self.delete_with_id("contacts", id),
+            DelInner::Namespace(id) => self.delete_with_id("namespaces", id),
         }
     }
 
     pub fn run(mut self, mut rx: mpsc::Receiver<Command>) {
-        loop {
-            if let Some(cmd) = rx.blocking_recv() {
-                tracing::debug!(?cmd, "database: received command");
-                match cmd {
-                    Command::Add { inner, resp } => {
-                        resp.send(self.handle_add(inner));
-                    }
-                    Command::Del { inner, resp } => {
-                        resp.send(self.handle_del(inner));
-                    }
-                    Command::GetRendezvous(resp) => {
-                        resp.send(self.get_rendezvous_list());
-                    }
-                    Command::GetUsedServices(resp) => {
-                        resp.send(self.get_used_services());
-                    }
-                    Command::GetProvidedServices(resp) => {
-                        resp.send(self.get_provided_services());
+        while let Some(cmd) = rx.blocking_recv() {
+            tracing::debug!(?cmd, "database: received command");
+            match cmd {
+                Command::Add { inner, resp } => {
+                    let _ = resp.send(self.handle_add(inner));
+                }
+                Command::Del { inner, resp } => {
+                    let _ = resp.send(self.handle_del(inner));
+                }
+                Command::GetRendezvous(resp) => {
+                    let _ = resp.send(self.get_rendezvous_list());
+                }
+                Command::GetUsedServices(resp) => {
+                    let _ = resp.send(self.get_used_services());
+                }
+                Command::GetProvidedServices(resp) => {
+                    let _ = resp.send(self.get_provided_services());
+                }
+                Command::GetAddressBook(resp) => {
+                    let _ = resp.send(self.get_address_book());
+                }
+                Command::GetContacts(resp) => {
+                    let _ = resp.send(self.get_contacts());
+                }
+                Command::ResolveAlias(alias, resp) => {
+                    let _ = resp.send(self.resolve_alias(&alias));
+                }
+                Command::GetNamespaces(resp) => {
+                    let _ = resp.send(self.get_namespaces());
+                }
+                Command::SavePeer(peer) => {
+                    if let Err(error) = self.save_peer(&peer) {
+                        tracing::error!(?error, peer_id = %peer.peer_id, "save peer error");
                     }
                 }
-            } else {
-                break;
+                Command::Close => break,
             }
         }
+        if let Err((_, error)) = self.conn.close() {
+            tracing::error!(?error, "close database error");
+        }
     }
 
     pub fn path(&self) -> Option<&str> {
         self.conn.path()
     }
 }
+
+// Persist peer addresses learned by the network so they survive restarts
+pub async fn record_peers(
+    mut known_peers: mpsc::UnboundedReceiver<KnownPeer>,
+    db_tx: mpsc::Sender<Command>,
+) {
+    while let Some(peer) = known_peers.recv().await {
+        if db_tx.send(Command::SavePeer(peer)).await.is_err() {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn db() -> DataBase {
+        let mut db = DataBase::new(Path::new(":memory:")).unwrap();
+        db.init().unwrap();
+        db.migrate().unwrap();
+        db
+    }
+
+    #[test]
+    fn expired_peers_are_forgotten() {
+        let mut db = db();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        let peer = |last_seen| KnownPeer {
+            peer_id: PeerId::random(),
+            addresses: vec!["/ip4/10.0.0.4/tcp/4001".parse().unwrap()],
+            first_seen: 0,
+            last_seen,
+        };
+        let recent = peer(now);
+        db.save_peer(&recent).unwrap();
+        db.save_peer(&peer(now - PEER_EXPIRY.as_secs() - 1)).unwrap();
+        let peers = db.get_peers().unwrap();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].peer_id, recent.peer_id);
+        let rows: u32 = db
+            .conn
+            .query_row("SELECT COUNT(*) FROM peers", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(rows, 1);
+    }
+}
@@ -1,2 +0,0 @@
-// Automatically generated mod.rs
-pub mod proxy;
@@ -1,83 +0,0 @@
-// Automatically generated rust module for 'message.proto' file
-
-#![allow(non_snake_case)]
-#![allow(non_upper_case_globals)]
-#![allow(non_camel_case_types)]
-#![allow(unused_imports)]
-#![allow(unknown_lints)]
-#![allow(clippy::all)]
-#![cfg_attr(rustfmt, rustfmt_skip)]
-
-
-use quick_protobuf::{MessageInfo, MessageRead, MessageWrite, BytesReader, Writer, WriterBackend, Result};
-use quick_protobuf::sizeofs::*;
-use super::super::*;
-
-#[allow(clippy::derive_partial_eq_without_eq)]
-#[derive(Default, Debug, PartialEq, Clone)]
-pub struct UseServiceReq {
-    pub host: String,
-    pub port: u32,
-}
-
-impl<'a> MessageRead<'a> for UseServiceReq {
-    fn from_reader(r: &mut BytesReader, bytes: &'a [u8]) -> Result<Self> {
-        let mut msg = Self::default();
-        while !r.is_eof() {
-            match r.next_tag(bytes) {
-                Ok(10) => msg.host = r.read_string(bytes)?.to_owned(),
-                Ok(16) => msg.port = r.read_uint32(bytes)?,
-                Ok(t) => { r.read_unknown(bytes, t)?; }
-                Err(e) => return Err(e),
-            }
-        }
-        Ok(msg)
-    }
-}
-
-impl MessageWrite for UseServiceReq {
-    fn get_size(&self) -> usize {
-        0
-        + 1 + sizeof_len(self.host.len())
-        + 1 + sizeof_varint(*(&self.port) as u64)
-    }
-
-    fn write_message<W: WriterBackend>(&self, w: &mut Writer<W>) -> Result<()> {
-        w.write_with_tag(10, |w| w.write_string(&self.host))?;
-        w.write_with_tag(16, |w| w.write_uint32(*&self.port))?;
-        Ok(())
-    }
-}
-
-#[allow(clippy::derive_partial_eq_without_eq)]
-#[derive(Default, Debug, PartialEq, Clone)]
-pub struct UseServiceResp {
-    pub allowed: bool,
-}
-
-impl<'a> MessageRead<'a> for UseServiceResp {
-    fn from_reader(r: &mut BytesReader, bytes: &'a [u8]) -> Result<Self> {
-        let mut msg = Self::default();
-        while !r.is_eof() {
-            match r.next_tag(bytes) {
-                Ok(8) => msg.allowed = r.read_bool(bytes)?,
-                Ok(t) => { r.read_unknown(bytes, t)?; }
-                Err(e) => return Err(e),
-            }
-        }
-        Ok(msg)
-    }
-}
-
-impl MessageWrite for UseServiceResp {
-    fn get_size(&self) -> usize {
-        0
-        + 1 + sizeof_varint(*(&self.allowed) as u64)
-    }
-
-    fn write_message<W: WriterBackend>(&self, w: &mut Writer<W>) -> Result<()> {
-        w.write_with_tag(8, |w| w.write_bool(*&self.allowed))?;
-        Ok(())
-    }
-}
-
@@ -1,40 +1,65 @@
 use axum::{
     extract::{Json, State, Query},
-    http::StatusCode,
-    response::IntoResponse,
+    http::{header, StatusCode},
+    response::{
+        sse::{self, KeepAlive, Sse},
+        IntoResponse,
+    },
     routing::get,
-    Router,
 };
+use futures::Stream;
+use libp2p::PeerId;
 use std::net::{IpAddr, SocketAddr};
+use tracing_subscriber::{reload, EnvFilter};
 use tokio::sync::{
+    broadcast,
     mpsc::{error::SendError, Sender},
     oneshot::{self, error::RecvError},
 };
 
-use preers::data::{NetworkInfo, ProvideService, Rendezvous, UseService};
-use crate::{
-    app,
-    db::{self, AddInner, DelInner},
+use preers::data::{
+    Contact, DirectConnection, Event, LogLevel, Namespace, NetworkInfo, PeerAddress, ProvideService, Reloaded,
+    Rendezvous, SessionInfo, UseService,
 };
+use preers::network;
+use crate::config::{LogHandle, ReloadRequest};
+use crate::db::{self, AddInner, DelInner};
+use crate::service;
+use sd_notify::NotifyState;
 
-use serde::Deserialize;
+use serde::{de::DeserializeOwned, Deserialize};
 use http::Method;
 use tower_http::cors::{Any, CorsLayer};
+use utoipa::{IntoParams, OpenApi};
+use utoipa_axum::{router::OpenApiRouter, routes};
 
 #[derive(Clone)]
 struct AppState {
     db_tx: Sender<db::Command>,
-    app_tx: Sender<app::Command>,
+    app_tx: Sender<network::Command>,
+    reload_tx: Sender<ReloadRequest>,
+    log: LogHandle,
+    events: broadcast::Sender<Event>,
 }
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 enum Error {
     SendError,
     RecvError,
     DBError,
+    LogError,
+    BadRequest,
+    // a bad request with the reason given to the client
+    Invalid(&'static str),
 }
 
-#[derive(Deserialize)]
+#[derive(OpenApi)]
+#[openapi(info(title = "preers", description = "RESTful API of a preers node"))]
+struct ApiDoc;
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
 struct DeleteQuery {
     id: i64
 }
@@ -45,34 +70,26 @@ pub async fn serve_http(
     host: IpAddr,
     port: u16,
     db_tx: Sender<db::Command>,
-    app_tx: Sender<app::Command>,
+    app_tx: Sender<network::Command>,
+    reload_tx: Sender<ReloadRequest>,
+    log: LogHandle,
+    events: broadcast::Sender<Event>,
 ) {
     let cors = CorsLayer::new()
-    .allow_methods(vec![Method::GET, Method::POST, Method::DELETE])
+    .allow_methods(vec![Method::GET, Method::POST, Method::PUT, Method::DELETE])
     .allow_headers(Any)
     .allow_origin(Any);
 
-    let app_state = AppState { db_tx, app_tx };
-    let app = Router::new()
-        .route("/network_info", get(get_info))
-        .route(
-            "/rendezvous",
-            get(get_rendezvous)
-                .post(post_rendezvous)
-                .delete(delete_rendezvous),
-        )
-        .route(
-            "/provide_service",
-            get(get_provide_service)
-                .post(post_provide_service)
-                .delete(delete_provide_service),
-        )
-        .route(
-            "/use_service",
-            get(get_use_service)
-                .post(post_use_service)
-                .delete(delete_use_service),
-        )
+    let app_state = AppState {
+        db_tx,
+        app_tx,
+        reload_tx,
+        log,
+        events,
+    };
+    let (router, openapi) = api_router().split_for_parts();
+    let app = router
+        .route("/openapi.json", get(move || async move { Json(openapi) }))
         .layer(cors)
         .with_state(app_state);
 
@@ -84,13 +101,220 @@ pub async fn serve_http(
         .expect("HTTP server should serve.");
 }
 
-async fn get_info(State(AppState { app_tx, .. }): State<AppState>) -> Result<Json<NetworkInfo>> {
+// Every documented route, the OpenAPI document is collected from the handlers registered here
+fn api_router() -> OpenApiRouter<AppState> {
+    OpenApiRouter::with_openapi(ApiDoc::openapi())
+        .routes(routes!(get_info))
+        .routes(routes!(get_events))
+        .routes(routes!(get_metrics))
+        .routes(routes!(get_sessions))
+        .routes(routes!(post_direct_connection))
+        .routes(routes!(post_shutdown))
+        .routes(routes!(post_reload))
+        .routes(routes!(get_log_level, put_log_level))
+        .routes(routes!(get_rendezvous, post_rendezvous, delete_rendezvous))
+        .routes(routes!(
+            get_provide_service,
+            post_provide_service,
+            delete_provide_service
+        ))
+        .routes(routes!(get_use_service, post_use_service, delete_use_service))
+        .routes(routes!(
+            get_address_book,
+            post_address_book,
+            delete_address_book
+        ))
+        .routes(routes!(get_contacts, post_contacts, delete_contacts))
+        .routes(routes!(get_namespaces, post_namespaces, delete_namespaces))
+}
+
+// The row of `rows` with `id`, unless another row is the same, i.e. the running node still
+// needs what it configures
+fn deleted<T>(
+    rows: Vec<T>,
+    id: i64,
+    id_of: impl Fn(&T) -> i64,
+    is_same: impl Fn(&T, &T) -> bool,
+) -> Option<T> {
+    let (deleted, rest): (Vec<T>, Vec<T>) = rows.into_iter().partition(|row| id_of(row) == id);
+    deleted
+        .into_iter()
+        .next()
+        .filter(|deleted| !rest.iter().any(|row| is_same(deleted, row)))
+}
+
+// Deserialize a request body whose `peer_id` may also be the alias of a contact
+async fn resolve_peer_id<T: DeserializeOwned>(
+    db_tx: &Sender<db::Command>,
+    mut body: serde_json::Value,
+) -> Result<T> {
+    if let Some(serde_json::Value::String(peer_id)) = body.get_mut("peer_id") {
+        if peer_id.parse::<PeerId>().is_err() {
+            let (resp_tx, resp_rx) = oneshot::channel();
+            db_tx
+                .send(db::Command::ResolveAlias(peer_id.clone(), resp_tx))
+                .await?;
+            match resp_rx.await?? {
+                Some(resolved) => *peer_id = resolved.to_base58(),
+                None => {
+                    tracing::debug!(alias = %peer_id, "unknown peer alias");
+                    return Err(Error::BadRequest);
+                }
+            }
+        }
+    }
+    serde_json::from_value(body).map_err(|_| Error::BadRequest)
+}
+
+#[utoipa::path(
+    get,
+    path = "/network_info",
+    responses((status = 200, body = NetworkInfo))
+)]
+async fn get_info(
+    State(AppState { db_tx, app_tx, .. }): State<AppState>,
+) -> Result<Json<NetworkInfo>> {
     tracing::debug!("getting network info");
     let (resp_tx, resp_rx) = oneshot::channel();
-    app_tx.send(app::Command::GetNetworkInfo(resp_tx)).await?;
+    app_tx.send(network::Command::GetNetworkInfo(resp_tx)).await?;
+    let mut network_info = resp_rx.await?;
+
+    let (resp_tx, resp_rx) = oneshot::channel();
+    db_tx.send(db::Command::GetContacts(resp_tx)).await?;
+    let contacts = resp_rx.await??;
+    for peer in network_info.peers.iter_mut() {
+        peer.alias = contacts
+            .iter()
+            .find(|contact| contact.peer_id == peer.peer_id)
+            .map(|contact| contact.alias.clone());
+    }
+    Ok(Json(network_info))
+}
+
+#[utoipa::path(
+    get,
+    path = "/events",
+    responses((status = 200, description = "Server-sent events stream", content_type = "text/event-stream", body = Event))
+)]
+async fn get_events(
+    State(AppState { events, .. }): State<AppState>,
+) -> Sse<impl Stream<Item = std::result::Result<sse::Event, axum::Error>>> {
+    let stream = futures::stream::unfold(events.subscribe(), |mut rx| async move {
+        loop {
+            match rx.recv().await {
+                Ok(event) => return Some((sse::Event::default().json_data(event), rx)),
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    tracing::warn!(%skipped, "events subscriber lagged");
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    });
+    Sse::new(stream).keep_alive(KeepAlive::default())
+}
+
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    responses((status = 200, description = "Metrics in the OpenMetrics text format", content_type = "application/openmetrics-text", body = String))
+)]
+async fn get_metrics(State(AppState { app_tx, .. }): State<AppState>) -> Result<impl IntoResponse> {
+    let (resp_tx, resp_rx) = oneshot::channel();
+    app_tx.send(network::Command::GetMetrics(resp_tx)).await?;
+    Ok((
+        [(
+            header::CONTENT_TYPE,
+            "application/openmetrics-text; version=1.0.0; charset=utf-8",
+        )],
+        resp_rx.await?,
+    ))
+}
+
+#[utoipa::path(
+    get,
+    path = "/sessions",
+    responses((status = 200, description = "Sessions being proxied, oldest first", body = Vec<SessionInfo>))
+)]
+async fn get_sessions(State(AppState { app_tx, .. }): State<AppState>) -> Result<Json<Vec<SessionInfo>>> {
+    let (resp_tx, resp_rx) = oneshot::channel();
+    app_tx.send(network::Command::GetSessions(resp_tx)).await?;
     Ok(Json(resp_rx.await?))
 }
 
+#[utoipa::path(
+    post,
+    path = "/direct_connection",
+    request_body(content = DirectConnection, description = "`peer_id` may be the alias of a contact"),
+    responses((status = 200, description = "Attempting, outcomes are reported as hole punches"), (status = 400, description = "Unknown peer or no address to dial"))
+)]
+async fn post_direct_connection(
+    State(AppState { db_tx, app_tx, .. }): State<AppState>,
+    Json(body): Json<serde_json::Value>,
+) -> Result<()> {
+    let DirectConnection { peer_id } = resolve_peer_id(&db_tx, body).await?;
+    let (resp_tx, resp_rx) = oneshot::channel();
+    app_tx
+        .send(network::Command::ConnectDirect(peer_id, resp_tx))
+        .await?;
+    if !resp_rx.await? {
+        return Err(Error::BadRequest);
+    }
+    Ok(())
+}
+
+#[utoipa::path(
+    post,
+    path = "/shutdown",
+    responses((status = 200, description = "Shutting down, in-flight sessions are drained first"))
+)]
+async fn post_shutdown(State(AppState { app_tx, .. }): State<AppState>) -> Result<()> {
+    service::notify(&[NotifyState::Stopping]);
+    app_tx.send(network::Command::Shutdown).await?;
+    Ok(())
+}
+
+#[utoipa::path(
+    post,
+    path = "/reload",
+    responses((status = 200, body = Reloaded), (status = 400, description = "Invalid settings, nothing reloaded"))
+)]
+async fn post_reload(State(AppState { reload_tx, .. }): State<AppState>) -> Result<Json<Reloaded>> {
+    let (resp_tx, resp_rx) = oneshot::channel();
+    reload_tx.send(resp_tx).await?;
+    resp_rx.await?.map(Json).map_err(|_| Error::BadRequest)
+}
+
+#[utoipa::path(
+    get,
+    path = "/log_level",
+    responses((status = 200, body = LogLevel))
+)]
+async fn get_log_level(State(AppState { log, .. }): State<AppState>) -> Result<Json<LogLevel>> {
+    let directives = log.with_current(|filter| filter.to_string())?;
+    Ok(Json(LogLevel { directives }))
+}
+
+#[utoipa::path(
+    put,
+    path = "/log_level",
+    request_body = LogLevel,
+    responses((status = 200, description = "Applied until the next reload"), (status = 400, description = "Invalid directives"))
+)]
+async fn put_log_level(
+    State(AppState { log, .. }): State<AppState>,
+    Json(LogLevel { directives }): Json<LogLevel>,
+) -> Result<()> {
+    let filter = EnvFilter::try_new(&directives).map_err(|_| Error::BadRequest)?;
+    log.reload(filter)?;
+    tracing::info!(%directives, "log level changed");
+    Ok(())
+}
+
+#[utoipa::path(
+    get,
+    path = "/rendezvous",
+    responses((status = 200, body = Vec<Rendezvous>))
+)]
 async fn get_rendezvous(
     State(AppState { db_tx, .. }): State<AppState>,
 ) -> Result<Json<Vec<Rendezvous>>> {
@@ -99,8 +323,14 @@ async fn get_rendezvous(
     Ok(Json(resp_rx.await??))
 }
 
+#[utoipa::path(
+    post,
+    path = "/rendezvous",
+    request_body = Rendezvous,
+    responses((status = 200, body = Rendezvous))
+)]
 async fn post_rendezvous(
-    State(AppState { db_tx, app_tx }): State<AppState>,
+    State(AppState { db_tx, app_tx, .. }): State<AppState>,
     Json(mut rendezvous): Json<Rendezvous>,
 ) -> Result<Json<Rendezvous>> {
     let (resp_tx, resp_rx) = oneshot::channel();
@@ -112,7 +342,7 @@ async fn post_rendezvous(
         .await?;
 
     app_tx
-        .send(app::Command::AddRendezvous(rendezvous.multiaddr.clone()))
+        .send(network::Command::AddRendezvous(rendezvous.multiaddr.clone()))
         .await?;
 
     let rendezvous_id = resp_rx.await?;
@@ -121,6 +351,12 @@ async fn post_rendezvous(
 }
 
 // TODO: delete rendezvous realtime
+#[utoipa::path(
+    delete,
+    path = "/rendezvous",
+    params(DeleteQuery),
+    responses((status = 200))
+)]
 async fn delete_rendezvous(
     State(AppState { db_tx, .. }): State<AppState>,
     Query(params): Query<DeleteQuery>,
@@ -135,6 +371,11 @@ async fn delete_rendezvous(
     Ok(resp_rx.await??)
 }
 
+#[utoipa::path(
+    get,
+    path = "/provide_service",
+    responses((status = 200, body = Vec<ProvideService>))
+)]
 async fn get_provide_service(
     State(AppState { db_tx, .. }): State<AppState>,
 ) -> Result<Json<Vec<ProvideService>>> {
@@ -145,8 +386,14 @@ async fn get_provide_service(
     Ok(Json(resp_rx.await??))
 }
 
+#[utoipa::path(
+    post,
+    path = "/provide_service",
+    request_body = ProvideService,
+    responses((status = 200, body = ProvideService))
+)]
 async fn post_provide_service(
-    State(AppState { db_tx, app_tx }): State<AppState>,
+    State(AppState { db_tx, app_tx, .. }): State<AppState>,
     Json(mut provide_service): Json<ProvideService>,
 ) -> Result<Json<ProvideService>> {
     let (resp_tx, resp_rx) = oneshot::channel();
@@ -158,7 +405,7 @@ async fn post_provide_service(
         .await?;
 
     app_tx
-        .send(app::Command::ProvideService(provide_service.clone()))
+        .send(network::Command::ProvideService(provide_service.clone()))
         .await?;
 
     let id = resp_rx.await?;
@@ -166,10 +413,19 @@ async fn post_provide_service(
     Ok(Json(provide_service))
 }
 
+#[utoipa::path(
+    delete,
+    path = "/provide_service",
+    params(DeleteQuery),
+    responses((status = 200))
+)]
 async fn delete_provide_service(
-    State(AppState { db_tx, .. }): State<AppState>,
+    State(AppState { db_tx, app_tx, .. }): State<AppState>,
     Query(params): Query<DeleteQuery>
 ) -> Result<()> {
+    let (resp_tx, resp_rx) = oneshot::channel();
+    db_tx.send(db::Command::GetProvidedServices(resp_tx)).await?;
+    let provided_services = resp_rx.await??;
     let (resp_tx, resp_rx) = oneshot::channel();
     db_tx
         .send(db::Command::Del {
@@ -177,9 +433,22 @@ async fn delete_provide_service(
             resp: resp_tx,
         })
         .await?;
-    Ok(resp_rx.await??)
+    resp_rx.await??;
+
+    let is_same = |a: &ProvideService, b: &ProvideService| (&a.host, a.port) == (&b.host, b.port);
+    if let Some(deleted) = deleted(provided_services, params.id, |ps| ps.id, is_same) {
+        app_tx
+            .send(network::Command::StopProvidingService(deleted))
+            .await?;
+    }
+    Ok(())
 }
 
+#[utoipa::path(
+    get,
+    path = "/use_service",
+    responses((status = 200, body = Vec<UseService>))
+)]
 async fn get_use_service(
     State(AppState { db_tx, .. }): State<AppState>,
 ) -> Result<Json<Vec<UseService>>> {
@@ -188,10 +457,17 @@ async fn get_use_service(
     Ok(Json(resp_rx.await??))
 }
 
+#[utoipa::path(
+    post,
+    path = "/use_service",
+    request_body(content = UseService, description = "`peer_id` may be the alias of a contact"),
+    responses((status = 200, body = UseService), (status = 400, description = "Unknown peer"))
+)]
 async fn post_use_service(
-    State(AppState { db_tx, app_tx }): State<AppState>,
-    Json(mut use_service): Json<UseService>,
+    State(AppState { db_tx, app_tx, .. }): State<AppState>,
+    Json(body): Json<serde_json::Value>,
 ) -> Result<Json<UseService>> {
+    let mut use_service: UseService = resolve_peer_id(&db_tx, body).await?;
     let (resp_tx, resp_rx) = oneshot::channel();
     db_tx
         .send(db::Command::Add {
@@ -201,7 +477,7 @@ async fn post_use_service(
         .await?;
 
     app_tx
-        .send(app::Command::UseService(use_service.clone()))
+        .send(network::Command::UseService(use_service.clone()))
         .await?;
 
     let id = resp_rx.await?;
@@ -209,10 +485,19 @@ async fn post_use_service(
     Ok(Json(use_service))
 }
 
+#[utoipa::path(
+    delete,
+    path = "/use_service",
+    params(DeleteQuery),
+    responses((status = 200))
+)]
 async fn delete_use_service(
-    State(AppState { db_tx, .. }): State<AppState>,
+    State(AppState { db_tx, app_tx, .. }): State<AppState>,
     Query(params): Query<DeleteQuery>,
 ) -> Result<()> {
+    let (resp_tx, resp_rx) = oneshot::channel();
+    db_tx.send(db::Command::GetUsedServices(resp_tx)).await?;
+    let used_services = resp_rx.await??;
     let (resp_tx, resp_rx) = oneshot::channel();
     db_tx
         .send(db::Command::Del {
@@ -220,10 +505,242 @@ async fn delete_use_service(
             resp: resp_tx,
         })
         .await?;
+    resp_rx.await??;
+
+    let is_same = |a: &UseService, b: &UseService| a.forwarder_port == b.forwarder_port;
+    if let Some(deleted) = deleted(used_services, params.id, |us| us.id, is_same) {
+        app_tx
+            .send(network::Command::StopUsingService(deleted.forwarder_port))
+            .await?;
+    }
+    Ok(())
+}
+
+#[utoipa::path(
+    get,
+    path = "/address_book",
+    responses((status = 200, body = Vec<PeerAddress>))
+)]
+async fn get_address_book(
+    State(AppState { db_tx, .. }): State<AppState>,
+) -> Result<Json<Vec<PeerAddress>>> {
+    let (resp_tx, resp_rx) = oneshot::channel();
+    db_tx.send(db::Command::GetAddressBook(resp_tx)).await?;
+    Ok(Json(resp_rx.await??))
+}
+
+#[utoipa::path(
+    post,
+    path = "/address_book",
+    request_body(content = PeerAddress, description = "`peer_id` may be the alias of a contact"),
+    responses((status = 200, body = PeerAddress), (status = 400, description = "Unknown peer"))
+)]
+async fn post_address_book(
+    State(AppState { db_tx, app_tx, .. }): State<AppState>,
+    Json(body): Json<serde_json::Value>,
+) -> Result<Json<PeerAddress>> {
+    let mut peer_address: PeerAddress = resolve_peer_id(&db_tx, body).await?;
+    let (resp_tx, resp_rx) = oneshot::channel();
+    db_tx
+        .send(db::Command::Add {
+            inner: AddInner::PeerAddress(peer_address.clone()),
+            resp: resp_tx,
+        })
+        .await?;
+
+    app_tx
+        .send(network::Command::AddPeerAddress(peer_address.clone()))
+        .await?;
+
+    let id = resp_rx.await?;
+    peer_address.id = id?;
+    Ok(Json(peer_address))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/address_book",
+    params(DeleteQuery),
+    responses((status = 200))
+)]
+async fn delete_address_book(
+    State(AppState { db_tx, app_tx, .. }): State<AppState>,
+    Query(params): Query<DeleteQuery>,
+) -> Result<()> {
+    let (resp_tx, resp_rx) = oneshot::channel();
+    db_tx.send(db::Command::GetAddressBook(resp_tx)).await?;
+    let address_book = resp_rx.await??;
+    let (resp_tx, resp_rx) = oneshot::channel();
+    db_tx
+        .send(db::Command::Del {
+            inner: DelInner::PeerAddress(params.id),
+            resp: resp_tx,
+        })
+        .await?;
+    resp_rx.await??;
+
+    let is_same = |a: &PeerAddress, b: &PeerAddress| {
+        (a.peer_id, &a.multiaddr) == (b.peer_id, &b.multiaddr)
+    };
+    if let Some(deleted) = deleted(address_book, params.id, |pa| pa.id, is_same) {
+        app_tx
+            .send(network::Command::RemovePeerAddress(deleted))
+            .await?;
+    }
+    Ok(())
+}
+
+#[utoipa::path(
+    get,
+    path = "/contacts",
+    responses((status = 200, body = Vec<Contact>))
+)]
+async fn get_contacts(
+    State(AppState { db_tx, .. }): State<AppState>,
+) -> Result<Json<Vec<Contact>>> {
+    let (resp_tx, resp_rx) = oneshot::channel();
+    db_tx.send(db::Command::GetContacts(resp_tx)).await?;
+    Ok(Json(resp_rx.await??))
+}
+
+#[utoipa::path(
+    post,
+    path = "/contacts",
+    request_body = Contact,
+    responses(
+        (status = 200, body = Contact),
+        (status = 400, description = "The alias is a peer ID or already taken")
+    )
+)]
+async fn post_contacts(
+    State(AppState { db_tx, .. }): State<AppState>,
+    Json(mut contact): Json<Contact>,
+) -> Result<Json<Contact>> {
+    // a peer ID is never resolved as an alias
+    if contact.alias.parse::<PeerId>().is_ok() {
+        return Err(Error::Invalid("alias must not be a peer ID"));
+    }
+    let (resp_tx, resp_rx) = oneshot::channel();
+    db_tx
+        .send(db::Command::Add {
+            inner: AddInner::Contact(contact.clone()),
+            resp: resp_tx,
+        })
+        .await?;
+    contact.id = resp_rx.await?.map_err(|error| match error.sqlite_error_code() {
+        Some(rusqlite::ErrorCode::ConstraintViolation) => {
+            Error::Invalid("alias is already taken")
+        }
+        _ => Error::DBError,
+    })?;
+    Ok(Json(contact))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/contacts",
+    params(DeleteQuery),
+    responses((status = 200))
+)]
+async fn delete_contacts(
+    State(AppState { db_tx, .. }): State<AppState>,
+    Query(params): Query<DeleteQuery>,
+) -> Result<()> {
+    let (resp_tx, resp_rx) = oneshot::channel();
+    db_tx
+        .send(db::Command::Del {
+            inner: DelInner::Contact(params.id),
+            resp: resp_tx,
+        })
+        .await?;
     Ok(resp_rx.await??)
 }
 
+#[utoipa::path(
+    get,
+    path = "/namespaces",
+    responses((status = 200, body = Vec<Namespace>))
+)]
+async fn get_namespaces(
+    State(AppState { db_tx, .. }): State<AppState>,
+) -> Result<Json<Vec<Namespace>>> {
+    let (resp_tx, resp_rx) = oneshot::channel();
+    db_tx.send(db::Command::GetNamespaces(resp_tx)).await?;
+    Ok(Json(resp_rx.await??))
+}
+
+#[utoipa::path(
+    post,
+    path = "/namespaces",
+    request_body = Namespace,
+    responses((status = 200, body = Namespace), (status = 400, description = "Invalid or reserved name"))
+)]
+async fn post_namespaces(
+    State(AppState { db_tx, app_tx, .. }): State<AppState>,
+    Json(mut namespace): Json<Namespace>,
+) -> Result<Json<Namespace>> {
+    // "relay" is where relays register, not a group of peers
+    if namespace.name == "relay"
+        || libp2p::rendezvous::Namespace::new(namespace.name.clone()).is_err()
+    {
+        return Err(Error::BadRequest);
+    }
+    let (resp_tx, resp_rx) = oneshot::channel();
+    db_tx
+        .send(db::Command::Add {
+            inner: AddInner::Namespace(namespace.name.clone()),
+            resp: resp_tx,
+        })
+        .await?;
+    namespace.id = resp_rx.await??;
+
+    app_tx
+        .send(network::Command::AddNamespace(namespace.name.clone()))
+        .await?;
+    Ok(Json(namespace))
+}
+
+#[utoipa::path(
+    delete,
+    path = "/namespaces",
+    params(DeleteQuery),
+    responses((status = 200))
+)]
+async fn delete_namespaces(
+    State(AppState { db_tx, app_tx, .. }): State<AppState>,
+    Query(params): Query<DeleteQuery>,
+) -> Result<()> {
+    let (resp_tx, resp_rx) = oneshot::channel();
+    db_tx.send(db::Command::GetNamespaces(resp_tx)).await?;
+    let namespace = resp_rx
+        .await??
+        .into_iter()
+        .find(|namespace| namespace.id == params.id);
+
+    let (resp_tx, resp_rx) = oneshot::channel();
+    db_tx
+        .send(db::Command::Del {
+            inner: DelInner::Namespace(params.id),
+            resp: resp_tx,
+        })
+        .await?;
+    resp_rx.await??;
+
+    if let Some(namespace) = namespace {
+        app_tx
+            .send(network::Command::RemoveNamespace(namespace.name))
+            .await?;
+    }
+    Ok(())
+}
+
 // TODO: Better error handling here.
+impl From<reload::Error> for Error {
+    fn from(_: reload::Error) -> Self {
+        Self::LogError
+    }
+}
+
 impl<T> From<SendError<T>> for Error {
     fn from(_: SendError<T>) -> Self {
         Self::SendError
@@ -244,6 +761,199 @@ impl From<db::Error> for Error {
 
 impl IntoResponse for Error {
     fn into_response(self) -> axum::response::Response {
-        StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        match self {
+            Self::BadRequest => StatusCode::BAD_REQUEST.into_response(),
+            Self::Invalid(reason) => (StatusCode::BAD_REQUEST, reason).into_response(),
+            _ => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, http::Request};
+    use std::collections::BTreeSet;
+    use tokio::sync::mpsc;
+    use tower::ServiceExt;
+
+    const METHODS: [Method; 4] = [Method::GET, Method::POST, Method::PUT, Method::DELETE];
+
+    // The served routes, with channels nobody receives on
+    fn router() -> axum::Router {
+        let (db_tx, _) = mpsc::channel(1);
+        let (app_tx, _) = mpsc::channel(1);
+        router_with(db_tx, app_tx)
+    }
+
+    // The served routes backed by an in-memory database, and the network commands they send
+    fn router_with_memory_db() -> (axum::Router, mpsc::Receiver<network::Command>) {
+        let mut db = db::DataBase::new(std::path::Path::new(":memory:")).unwrap();
+        db.init().unwrap();
+        db.migrate().unwrap();
+        let (db_tx, db_rx) = mpsc::channel(1);
+        std::thread::spawn(move || db.run(db_rx));
+        let (app_tx, app_rx) = mpsc::channel(8);
+        (router_with(db_tx, app_tx), app_rx)
+    }
+
+    fn router_with(db_tx: Sender<db::Command>, app_tx: Sender<network::Command>) -> axum::Router {
+        let (reload_tx, _) = mpsc::channel(1);
+        let (_, log) = reload::Layer::new(EnvFilter::default());
+        let (events, _) = broadcast::channel(1);
+        let (router, _) = api_router().split_for_parts();
+        router.with_state(AppState {
+            db_tx,
+            app_tx,
+            reload_tx,
+            log,
+            events,
+        })
+    }
+
+    // (path, method) of every operation in the OpenAPI document
+    fn documented() -> BTreeSet<(String, String)> {
+        let (_, openapi) = api_router().split_for_parts();
+        let mut documented = BTreeSet::new();
+        for (path, item) in openapi.paths.paths {
+            let unchecked = [&item.patch, &item.head, &item.options, &item.trace];
+            assert!(
+                unchecked.iter().all(|operation| operation.is_none()),
+                "{path} documents a method not checked here"
+            );
+            let operations = [
+                (Method::GET, item.get),
+                (Method::POST, item.post),
+                (Method::PUT, item.put),
+                (Method::DELETE, item.delete),
+            ];
+            for (method, operation) in operations {
+                if operation.is_some() {
+                    documented.insert((path.clone(), method.to_string()));
+                }
+            }
+        }
+        documented
+    }
+
+    // Whether the router routes `method` on `path` to a handler, rather than answering 404 or 405
+    async fn is_served(router: &axum::Router, path: &str, method: &Method) -> bool {
+        let request = Request::builder()
+            .method(method)
+            .uri(path)
+            .body(Body::empty())
+            .unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        !matches!(
+            response.status(),
+            StatusCode::NOT_FOUND | StatusCode::METHOD_NOT_ALLOWED
+        )
+    }
+
+    #[tokio::test]
+    async fn documented_routes_are_served() {
+        let router = router();
+        for (path, method) in documented() {
+            assert!(
+                is_served(&router, &path, &method.parse().unwrap()).await,
+                "{method} {path} is documented but not served"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn undocumented_methods_are_not_served() {
+        let router = router();
+        let documented = documented();
+        for (path, _) in &documented {
+            for method in METHODS {
+                if is_served(&router, path, &method).await {
+                    assert!(
+                        documented.contains(&(path.clone(), method.to_string())),
+                        "{method} {path} is served but not documented"
+                    );
+                }
+            }
+        }
+    }
+
+    // Status and body of a contact POST
+    async fn add_contact(
+        router: &axum::Router,
+        alias: &str,
+        peer_id: PeerId,
+    ) -> (StatusCode, String) {
+        let body = serde_json::json!({ "id": 0, "alias": alias, "peer_id": peer_id.to_base58() });
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri("/contacts")
+            .header("content-type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = router.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn duplicate_aliases_are_rejected() {
+        let (router, _app_rx) = router_with_memory_db();
+        let (status, _) = add_contact(&router, "alice", PeerId::random()).await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, body) = add_contact(&router, "alice", PeerId::random()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body, "alias is already taken");
+    }
+
+    #[tokio::test]
+    async fn peer_id_aliases_are_rejected() {
+        let (router, _app_rx) = router_with_memory_db();
+        let alias = PeerId::random().to_base58();
+        let (status, body) = add_contact(&router, &alias, PeerId::random()).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body, "alias must not be a peer ID");
+    }
+
+    #[tokio::test]
+    async fn deleted_addresses_are_removed_from_the_network() {
+        let (router, mut app_rx) = router_with_memory_db();
+        let peer_id = PeerId::random();
+        let multiaddr: libp2p::Multiaddr = "/ip4/10.0.0.4/tcp/4001".parse().unwrap();
+        let body = serde_json::json!({
+            "id": 0,
+            "peer_id": peer_id.to_base58(),
+            "multiaddr": multiaddr.to_string(),
+        });
+        // the same address twice, it is only removed with the last row
+        for _ in 0..2 {
+            let request = Request::builder()
+                .method(Method::POST)
+                .uri("/address_book")
+                .header("content-type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            let response = router.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            assert!(matches!(app_rx.recv().await, Some(network::Command::AddPeerAddress(_))));
+        }
+        for id in 1..=2 {
+            let request = Request::builder()
+                .method(Method::DELETE)
+                .uri(format!("/address_book?id={id}"))
+                .body(Body::empty())
+                .unwrap();
+            let response = router.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        match app_rx.try_recv() {
+            Ok(network::Command::RemovePeerAddress(removed)) => {
+                assert_eq!((removed.peer_id, removed.multiaddr), (peer_id, multiaddr));
+            }
+            _ => panic!("the address should be removed once"),
+        }
+        assert!(app_rx.try_recv().is_err());
     }
 }
@@ -1,33 +1,63 @@
-mod app;
+mod config;
 mod db;
 mod http;
-mod proxy;
+mod service;
 
-use app::Network;
-use clap::Parser;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use config::{LogHandle, Reloader};
 use db::DataBase;
-use libp2p::identity::{self, ed25519};
+use sd_notify::NotifyState;
+use service::{InstallServiceArgs, PidFile};
+use libp2p::{
+    identity::{self, ed25519},
+    pnet::PreSharedKey,
+    Multiaddr, PeerId,
+};
 use std::{
     error::Error,
     net::{IpAddr, Ipv4Addr},
-    path::PathBuf,
+    num::NonZeroU32,
+    path::{Path, PathBuf},
+    time::Duration,
 };
-use tokio::sync::{mpsc, oneshot};
-use tracing_subscriber::EnvFilter;
-
-use preers::DEFAULT_HTTP_PORT;
-
-type Responder<T> = oneshot::Sender<T>;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
+use tracing_appender::{
+    non_blocking::WorkerGuard,
+    rolling::{RollingFileAppender, Rotation},
+};
+use tracing_subscriber::{fmt::writer::BoxMakeWriter, prelude::*, reload};
 
+use preers::{Command, Network, NetworkConfig, RelayConfig, TlsCertificate, TransportConfig, DEFAULT_HTTP_PORT, MPSC_CHANNEL_SIZE};
 
 const DEFAULT_P2P_PORT: u16 = 0;
-const MPSC_CHANNEL_SIZE: usize = 256;
-const DEFAULT_DB_PATH: &str = "./preers.db";
+const DEFAULT_DB_PATH: &str = "preers.db";
+const DEFAULT_LOG_FILE: &str = "logs/preers.log";
 
+// Flags given more than once, e.g. in the config file and on the command line, take the last
+// value
 #[derive(Parser)]
 #[command(name = "preers")]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, args_override_self = true)]
 struct Cli {
+    #[arg(
+        long,
+        help = "TOML file of flags by long name, e.g. `relay = true`, re-read on SIGHUP and POST /reload"
+    )]
+    config: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "directory holding the database, log files, keys and pidfile, relative paths of other flags are taken from it"
+    )]
+    data_dir: Option<PathBuf>,
+
+    #[arg(long, help = "write the process ID to this file while running")]
+    pidfile: Option<PathBuf>,
+
+    #[command(flatten)]
+    logging: LogArgs,
+
     #[arg(
         short,
         long,
@@ -36,7 +66,10 @@ struct Cli {
     )]
     port: u16,
 
-    #[arg(long, help = "path to database, default is './preers.db'")]
+    #[command(flatten)]
+    transport: TransportArgs,
+
+    #[arg(long, help = "path to database, default is 'preers.db' in --data-dir or the working directory")]
     db: Option<PathBuf>,
 
     #[arg(long, default_value_t = DEFAULT_HTTP_PORT, help = "port for restful api")]
@@ -45,29 +78,318 @@ struct Cli {
     #[arg(long, help = "serve as a relay")]
     relay: bool,
 
+    #[command(flatten)]
+    relay_limits: RelayArgs,
+
     #[arg(long, help = "serve as a rendezvous point")]
     rendezvous: bool,
 
+    #[arg(long, help = "discover peers on the local network via mDNS")]
+    mdns: bool,
+
+    #[arg(long, help = "find peers and relays through the Kademlia DHT")]
+    kad: bool,
+
+    #[arg(long, default_value_t = 2, help = "number of relays to keep reservations on")]
+    max_relays: usize,
+
+    #[arg(
+        long,
+        default_value_t = 0,
+        help = "seconds used services wait for a direct connection before proxying over a relay"
+    )]
+    direct_wait: u64,
+
+    #[arg(
+        long,
+        help = "close relayed connections to peers once connected directly, resetting sessions still proxied over them"
+    )]
+    close_relayed: bool,
+
+    #[arg(
+        long,
+        default_value_t = 10,
+        help = "seconds proxied sessions get to finish on shutdown"
+    )]
+    drain_timeout: u64,
+
     #[arg(long, help = "external address to this node")]
     external_address: Option<String>,
+
+    #[command(subcommand)]
+    command: Option<CliCommand>,
+}
+
+#[derive(Subcommand)]
+enum CliCommand {
+    /// Print a systemd unit running preers with the flags given before this command
+    InstallService(InstallServiceArgs),
+}
+
+// Where and how logs are written, stdout when no `--log-file` is given and there is no
+// `--data-dir` to write them to
+#[derive(Args)]
+#[command(next_help_heading = "Logging")]
+struct LogArgs {
+    #[arg(long, help = "log filter, e.g. info,preers=debug, default is RUST_LOG or info")]
+    log: Option<String>,
+
+    #[arg(long, value_enum, default_value_t = LogFormat::Text, help = "log format")]
+    log_format: LogFormat,
+
+    #[arg(
+        long,
+        help = "write logs to this file, e.g. /var/log/preers/preers.log, default is 'logs/preers.log' in --data-dir or stdout, '-' for stdout"
+    )]
+    log_file: Option<PathBuf>,
+
+    #[arg(long, value_enum, default_value_t = LogRotation::Daily, help = "how often to start a new log file")]
+    log_rotation: LogRotation,
+
+    #[arg(long, default_value_t = 7, help = "number of rotated log files to keep, 0 keeps all")]
+    log_max_files: usize,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum LogFormat {
+    Text,
+    Json,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum LogRotation {
+    Minutely,
+    Hourly,
+    Daily,
+    Never,
+}
+
+impl From<LogRotation> for Rotation {
+    fn from(rotation: LogRotation) -> Self {
+        match rotation {
+            LogRotation::Minutely => Rotation::MINUTELY,
+            LogRotation::Hourly => Rotation::HOURLY,
+            LogRotation::Daily => Rotation::DAILY,
+            LogRotation::Never => Rotation::NEVER,
+        }
+    }
+}
+
+// Transports and listen addresses, any address on `--port` when no `--listen` is given
+#[derive(Args)]
+#[command(next_help_heading = "Transport")]
+struct TransportArgs {
+    #[arg(long, help = "address to listen on, e.g. /ip4/192.168.1.5/tcp/4001, can be repeated")]
+    listen: Vec<Multiaddr>,
+
+    #[arg(long, help = "TCP port to listen on, default is --port")]
+    tcp_port: Option<u16>,
+
+    #[arg(long, help = "UDP port to listen on for QUIC, default is --port")]
+    udp_port: Option<u16>,
+
+    #[arg(long, help = "disable TCP")]
+    no_tcp: bool,
+
+    #[arg(long, help = "disable QUIC")]
+    no_quic: bool,
+
+    #[arg(long, help = "don't listen on IPv6")]
+    no_ipv6: bool,
+
+    #[arg(long, help = "dial and listen over WebSocket")]
+    websocket: bool,
+
+    #[arg(long, default_value_t = 0, help = "TCP port to listen on for WebSocket, e.g. 443")]
+    ws_port: u16,
+
+    #[arg(
+        long,
+        requires = "ws_tls_key",
+        help = "PEM certificate chain to listen on WebSocket over TLS with"
+    )]
+    ws_tls_cert: Option<PathBuf>,
+
+    #[arg(long, requires = "ws_tls_cert", help = "PEM private key of --ws-tls-cert")]
+    ws_tls_key: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "pre-shared key file in the IPFS swarm.key format, only nodes with the same key can connect, disables QUIC"
+    )]
+    swarm_key: Option<PathBuf>,
+}
+
+// Limits of the relay server, libp2p defaults when not given
+#[derive(Args)]
+#[command(next_help_heading = "Relay")]
+struct RelayArgs {
+    #[arg(long, help = "max reservations held by all peers")]
+    relay_max_reservations: Option<usize>,
+
+    #[arg(long, help = "max reservations held by one peer")]
+    relay_max_reservations_per_peer: Option<usize>,
+
+    #[arg(long, help = "seconds a reservation lasts before it must be renewed")]
+    relay_reservation_duration: Option<u64>,
+
+    #[arg(long, help = "max circuits relayed for all peers")]
+    relay_max_circuits: Option<usize>,
+
+    #[arg(long, help = "max circuits relayed for one peer")]
+    relay_max_circuits_per_peer: Option<usize>,
+
+    #[arg(long, help = "seconds after which a circuit is closed")]
+    relay_max_circuit_duration: Option<u64>,
+
+    #[arg(long, help = "bytes after which a circuit is closed")]
+    relay_max_circuit_bytes: Option<u64>,
+
+    #[arg(long, help = "reservations and circuits one peer may request per minute")]
+    relay_rate_per_minute: Option<NonZeroU32>,
+
+    #[arg(long, help = "only relay for this peer, can be repeated")]
+    relay_allow: Vec<PeerId>,
+}
+
+impl From<RelayArgs> for RelayConfig {
+    fn from(args: RelayArgs) -> Self {
+        let default = RelayConfig::default();
+        Self {
+            max_reservations: args
+                .relay_max_reservations
+                .unwrap_or(default.max_reservations),
+            max_reservations_per_peer: args
+                .relay_max_reservations_per_peer
+                .unwrap_or(default.max_reservations_per_peer),
+            reservation_duration: args
+                .relay_reservation_duration
+                .map_or(default.reservation_duration, Duration::from_secs),
+            max_circuits: args.relay_max_circuits.unwrap_or(default.max_circuits),
+            max_circuits_per_peer: args
+                .relay_max_circuits_per_peer
+                .unwrap_or(default.max_circuits_per_peer),
+            max_circuit_duration: args
+                .relay_max_circuit_duration
+                .map_or(default.max_circuit_duration, Duration::from_secs),
+            max_circuit_bytes: args
+                .relay_max_circuit_bytes
+                .unwrap_or(default.max_circuit_bytes),
+            rate_per_minute: args.relay_rate_per_minute,
+            allowed_peers: args.relay_allow.into_iter().collect(),
+        }
+    }
+}
+
+// Transports of `--port` and the transport flags, reading the key and certificate files
+fn transport_config(args: TransportArgs, port: u16) -> Result<TransportConfig, Box<dyn Error>> {
+    let psk = match args.swarm_key {
+        Some(path) => {
+            let swarm_key = std::fs::read_to_string(&path)
+                .map_err(|error| format!("failed to read swarm key {}: {error}", path.display()))?;
+            let psk = swarm_key
+                .parse::<PreSharedKey>()
+                .map_err(|error| format!("invalid swarm key {}: {error}", path.display()))?;
+            Some(psk)
+        }
+        None => None,
+    };
+
+    let ws_tls = match (args.ws_tls_cert, args.ws_tls_key) {
+        (Some(cert), Some(key)) => {
+            let tls = TlsCertificate::from_pem(&std::fs::read(&cert)?, &std::fs::read(&key)?)
+                .map_err(|error| format!("invalid TLS certificate {}: {error}", cert.display()))?;
+            Some(tls)
+        }
+        _ => None,
+    };
+
+    Ok(TransportConfig {
+        tcp: !args.no_tcp,
+        quic: !args.no_quic,
+        ipv6: !args.no_ipv6,
+        websocket: args.websocket,
+        tcp_port: args.tcp_port.unwrap_or(port),
+        udp_port: args.udp_port.unwrap_or(port),
+        ws_port: args.ws_port,
+        ws_tls,
+        listen: args.listen,
+        psk,
+    })
+}
+
+fn init_logging(
+    args: &LogArgs,
+) -> Result<(LogHandle, Option<WorkerGuard>), Box<dyn Error>> {
+    let (log_filter, log) = reload::Layer::new(config::log_filter(args.log.as_deref())?);
+    let (writer, guard) = match &args.log_file {
+        Some(path) => {
+            let mut appender = RollingFileAppender::builder()
+                .rotation(args.log_rotation.into())
+                .max_log_files(args.log_max_files);
+            if let Some(prefix) = path.file_stem() {
+                appender = appender.filename_prefix(prefix.to_string_lossy());
+            }
+            if let Some(suffix) = path.extension() {
+                appender = appender.filename_suffix(suffix.to_string_lossy());
+            }
+            let dir = path
+                .parent()
+                .filter(|dir| !dir.as_os_str().is_empty())
+                .unwrap_or(Path::new("."));
+            std::fs::create_dir_all(dir)
+                .map_err(|error| format!("failed to create log directory {}: {error}", dir.display()))?;
+            let appender = appender
+                .build(dir)
+                .map_err(|error| format!("failed to open log file {}: {error}", path.display()))?;
+            let (writer, guard) = tracing_appender::non_blocking(appender);
+            (BoxMakeWriter::new(writer), Some(guard))
+        }
+        None => (BoxMakeWriter::new(std::io::stdout), None),
+    };
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(args.log_file.is_none());
+    let layer = match args.log_format {
+        LogFormat::Text => layer.boxed(),
+        LogFormat::Json => layer.json().boxed(),
+    };
+    tracing_subscriber::registry()
+        .with(log_filter)
+        .with(layer)
+        .init();
+    Ok((log, guard))
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    tracing_subscriber::fmt()
-        .with_env_filter(EnvFilter::from_default_env())
-        .init();
+    let cli = config::parse_cli().unwrap_or_else(|error| error.exit());
+
+    if let Some(CliCommand::InstallService(args)) = &cli.command {
+        return service::install_service(args, cli.drain_timeout);
+    }
 
-    let cli = Cli::parse();
+    if let Some(data_dir) = &cli.data_dir {
+        std::fs::create_dir_all(data_dir).map_err(|error| {
+            format!("failed to create data directory {}: {error}", data_dir.display())
+        })?;
+    }
+
+    // the log filter is swapped on reload and PUT /log_level, logs written to a file are
+    // flushed when the guard is dropped
+    let (log, _log_guard) = init_logging(&cli.logging)?;
+
+    // removed again once shut down
+    let _pidfile = cli.pidfile.map(PidFile::create).transpose()?;
 
     let db_path = cli.db.unwrap_or(DEFAULT_DB_PATH.into());
     let db_created = db_path.exists();
 
     let mut db = DataBase::new(&db_path)?;
-    println!("Opened database: {}", db.path().unwrap());
+    tracing::info!(path = db.path().unwrap(), "opened database");
     let ed25519_keypair = if !db_created {
         db.init()?;
-        println!("Database initialized");
+        tracing::info!("database initialized");
         let kp = ed25519::Keypair::generate();
         db.set_setting("keypair", kp.to_bytes().as_ref())?;
         kp
@@ -75,10 +397,12 @@ async fn main() -> Result<(), Box<dyn Error>> {
         let mut kp_bytes = db.get_setting("keypair")?;
         ed25519::Keypair::try_from_bytes(kp_bytes.as_mut_slice())?
     };
+    db.migrate()?;
 
     let keypair = identity::Keypair::from(ed25519_keypair);
 
-    println!("Peer ID: {}", keypair.public().to_peer_id());
+    let peer_id = keypair.public().to_peer_id();
+    tracing::info!(%peer_id, "local peer id");
 
     let rendezvous_list = db
         .get_rendezvous_list()?
@@ -88,10 +412,36 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     // TODO: handle intial rendezvous list and services together
     // Create libp2p application network eventloop
-    let mut network = Network::new(keypair, cli.relay, cli.rendezvous, rendezvous_list)?;
+    let mut network = Network::new(
+        keypair,
+        NetworkConfig {
+            transport: transport_config(cli.transport, cli.port)?,
+            relay: cli.relay.then(|| cli.relay_limits.into()),
+            rendezvous: cli.rendezvous,
+            mdns: cli.mdns,
+            kad: cli.kad,
+            max_relays: cli.max_relays,
+            direct_wait: Duration::from_secs(cli.direct_wait),
+            close_relayed: cli.close_relayed,
+            drain_timeout: Duration::from_secs(cli.drain_timeout),
+            rendezvous_list,
+        },
+    )?;
 
     let used_services = db.get_used_services()?;
     let provided_services = db.get_provided_services()?;
+    let known_peers = db.get_peers()?;
+    let address_book = db.get_address_book()?;
+    let namespaces = db
+        .get_namespaces()?
+        .into_iter()
+        .map(|x| x.name)
+        .collect();
+
+    // Static addresses first, they may bootstrap the DHT used to find providers of used services
+    for peer_address in address_book {
+        network.add_peer_address(peer_address);
+    }
 
     // Initialize network, start listening etc
     let maybe_external_address = if let Some(external_address) = cli.external_address {
@@ -100,15 +450,33 @@ async fn main() -> Result<(), Box<dyn Error>> {
     } else {
         None
     };
-    network.init(cli.port, used_services, provided_services, maybe_external_address)?;
+    network.init(
+        used_services,
+        provided_services,
+        known_peers,
+        namespaces,
+        maybe_external_address,
+    )?;
 
-    println!("Network initialized...");
+    tracing::info!("network initialized");
     let (db_tx, db_rx) = mpsc::channel(MPSC_CHANNEL_SIZE);
-    let (app_tx, app_rx) = mpsc::channel(MPSC_CHANNEL_SIZE);
+    let app_tx = network.handle();
 
     // Spawn sqlite database worker thread
-    let _ = tokio::task::spawn_blocking(move || db.run(db_rx));
-    println!("Database running...");
+    let db_worker = tokio::task::spawn_blocking(move || db.run(db_rx));
+    tracing::info!("database running");
+
+    // Remember peers and their addresses across restarts
+    tokio::spawn(db::record_peers(network.known_peers(), db_tx.clone()));
+
+    // Reload settings on SIGHUP and POST /reload
+    let (reload_tx, reload_rx) = mpsc::channel(MPSC_CHANNEL_SIZE);
+    let reloader = Reloader {
+        log: log.clone(),
+        db_tx: db_tx.clone(),
+        app_tx: app_tx.clone(),
+    };
+    tokio::spawn(reloader.run(reload_rx));
 
     // Spawn RESTful API http server
     tokio::spawn(http::serve_http(
@@ -116,10 +484,47 @@ async fn main() -> Result<(), Box<dyn Error>> {
         cli.http_port,
         db_tx.clone(),
         app_tx.clone(),
+        reload_tx,
+        log,
+        network.events(),
     ));
-    println!("HTTP listening on {}", cli.http_port);
+    tracing::info!(port = cli.http_port, "http listening");
+
+    // Shut down gracefully on Ctrl-C and SIGTERM
+    tokio::spawn(shutdown_on_signal(app_tx.clone()));
+
+    // Tell systemd the node is up, and keep pinging its watchdog while the network answers
+    service::notify(&[
+        NotifyState::Ready,
+        NotifyState::Status(&format!("Peer ID {peer_id}, HTTP on port {}", cli.http_port)),
+    ]);
+    tokio::spawn(service::watchdog(app_tx.clone()));
 
-    network.run(app_rx, app_tx).await;
+    network.run().await;
+
+    db_tx.send(db::Command::Close).await?;
+    db_worker.await?;
+    tracing::info!("shut down");
 
     Ok(())
 }
+
+async fn shutdown_on_signal(app_tx: mpsc::Sender<Command>) {
+    let mut terminate =
+        signal(SignalKind::terminate()).expect("should be able to handle SIGTERM");
+    let mut signaled = false;
+    loop {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+        if signaled {
+            tracing::warn!("received second shutdown signal, exiting without draining sessions");
+            std::process::exit(1);
+        }
+        signaled = true;
+        tracing::info!("received shutdown signal");
+        service::notify(&[NotifyState::Stopping]);
+        let _ = app_tx.send(Command::Shutdown).await;
+    }
+}
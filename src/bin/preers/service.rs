@@ -0,0 +1,162 @@
+//! Running preers as a system service: the pidfile, systemd notifications and the unit file of
+//! `install-service`.
+//!
+//! Notifications are only sent when started by systemd with `Type=notify`, i.e. when
+//! `NOTIFY_SOCKET` is set, and do nothing otherwise.
+
+use clap::Args;
+use sd_notify::NotifyState;
+use std::error::Error;
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
+
+use preers::Command;
+
+/// Pidfile written on start and removed when the node exits.
+pub struct PidFile(PathBuf);
+
+impl PidFile {
+    pub fn create(path: PathBuf) -> Result<Self, String> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir)
+                .map_err(|error| format!("failed to create pidfile directory {}: {error}", dir.display()))?;
+        }
+        std::fs::write(&path, format!("{}\n", std::process::id()))
+            .map_err(|error| format!("failed to write pidfile {}: {error}", path.display()))?;
+        Ok(Self(path))
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if let Err(error) = std::fs::remove_file(&self.0) {
+            tracing::warn!(path = %self.0.display(), %error, "failed to remove pidfile");
+        }
+    }
+}
+
+/// Tell systemd about the state of the node.
+pub fn notify(state: &[NotifyState]) {
+    if let Err(error) = sd_notify::notify(false, state) {
+        tracing::warn!(%error, "failed to notify systemd");
+    }
+}
+
+/// Ping the systemd watchdog as long as the network answers, so that a stuck node is restarted.
+pub async fn watchdog(app_tx: mpsc::Sender<Command>) {
+    let mut usec = 0;
+    if !sd_notify::watchdog_enabled(false, &mut usec) {
+        return;
+    }
+    // ping twice per watchdog timeout, as sd_watchdog_enabled(3) recommends
+    let period = Duration::from_micros(usec) / 2;
+    let mut interval = tokio::time::interval(period);
+    loop {
+        interval.tick().await;
+        let (resp_tx, resp_rx) = oneshot::channel();
+        if app_tx.send(Command::GetNetworkInfo(resp_tx)).await.is_err() {
+            break;
+        }
+        match tokio::time::timeout(period, resp_rx).await {
+            Ok(Ok(_)) => notify(&[NotifyState::Watchdog]),
+            Ok(Err(_)) => break,
+            Err(_) => tracing::warn!("network is not answering, skipping watchdog ping"),
+        }
+    }
+}
+
+#[derive(Args)]
+pub struct InstallServiceArgs {
+    #[arg(
+        long,
+        help = "write the unit to this file instead of stdout, e.g. /etc/systemd/system/preers.service"
+    )]
+    output: Option<PathBuf>,
+
+    #[arg(long, help = "user to run the service as, default is root")]
+    user: Option<String>,
+
+    #[arg(
+        long,
+        default_value_t = 30,
+        help = "seconds without a watchdog ping before systemd restarts the node, 0 disables the watchdog"
+    )]
+    watchdog: u64,
+}
+
+/// Write a systemd unit running this binary with the flags given before `install-service`.
+pub fn install_service(args: &InstallServiceArgs, drain_timeout: u64) -> Result<(), Box<dyn Error>> {
+    let unit = unit_file(args, drain_timeout)?;
+    match &args.output {
+        Some(path) => {
+            std::fs::write(path, unit)
+                .map_err(|error| format!("failed to write unit {}: {error}", path.display()))?;
+            println!("Wrote {}", path.display());
+            println!("Start it with: systemctl daemon-reload && systemctl enable --now preers");
+        }
+        None => print!("{unit}"),
+    }
+    Ok(())
+}
+
+// Relative paths of the flags, e.g. `--data-dir`, stay relative to the current directory by
+// running the service in it
+fn unit_file(args: &InstallServiceArgs, drain_timeout: u64) -> Result<String, Box<dyn Error>> {
+    let exe = std::env::current_exe()?;
+    let working_dir = std::env::current_dir()?;
+    let flags: Vec<OsString> = std::env::args_os()
+        .skip(1)
+        .take_while(|arg| arg != "install-service")
+        .collect();
+    let exec_start = std::iter::once(exe.into_os_string())
+        .chain(flags)
+        .map(|arg| quote(&arg.to_string_lossy()))
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    let mut unit = String::new();
+    unit.push_str("[Unit]\n");
+    unit.push_str("Description=preers peer-to-peer port forwarding\n");
+    unit.push_str("Wants=network-online.target\n");
+    unit.push_str("After=network-online.target\n");
+    unit.push('\n');
+    unit.push_str("[Service]\n");
+    unit.push_str("Type=notify\n");
+    unit.push_str(&format!("ExecStart={exec_start}\n"));
+    unit.push_str("ExecReload=/bin/kill -HUP $MAINPID\n");
+    unit.push_str(&format!(
+        "WorkingDirectory={}\n",
+        working_dir.to_string_lossy().replace('%', "%%")
+    ));
+    if let Some(user) = &args.user {
+        unit.push_str(&format!("User={user}\n"));
+    }
+    if args.watchdog > 0 {
+        unit.push_str(&format!("WatchdogSec={}\n", args.watchdog));
+    }
+    // sessions get --drain-timeout to finish, the rest of the shutdown is quick
+    unit.push_str(&format!("TimeoutStopSec={}\n", drain_timeout + 10));
+    unit.push_str("Restart=on-failure\n");
+    unit.push('\n');
+    unit.push_str("[Install]\n");
+    unit.push_str("WantedBy=multi-user.target\n");
+    Ok(unit)
+}
+
+// Quote an argument for systemd, which expands `%` specifiers and `$` variables in ExecStart
+fn quote(arg: &str) -> String {
+    let escaped = arg.replace('%', "%%").replace('$', "$$");
+    if !escaped.is_empty()
+        && !escaped
+            .chars()
+            .any(|c| c.is_whitespace() || matches!(c, '"' | '\'' | '\\' | ';'))
+    {
+        return escaped;
+    }
+    format!(
+        "\"{}\"",
+        escaped.replace('\\', "\\\\").replace('"', "\\\"")
+    )
+}
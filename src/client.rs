@@ -0,0 +1,273 @@
+//! Typed client for the RESTful API served by `preers`.
+
+use crate::data::{
+    Contact, DirectConnection, Event, Namespace, NetworkInfo, PeerAddress, ProvideService,
+    LogLevel, Reloaded, Rendezvous, SessionInfo, UseService,
+};
+use libp2p::{Multiaddr, PeerId};
+use reqwest::{IntoUrl, Url};
+use serde::{de::DeserializeOwned, Serialize};
+
+pub use reqwest::Error;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Clone, Debug)]
+pub struct Client {
+    http: reqwest::Client,
+    base_url: Url,
+}
+
+impl Client {
+    /// Client for a node serving its API on localhost at `port`.
+    pub fn new(port: u16) -> Self {
+        Self::with_base_url(format!("http://localhost:{port}"))
+            .expect("localhost url should parse")
+    }
+
+    /// Client for a node serving its API at `base_url`, e.g. `http://10.0.0.4:9843`.
+    pub fn with_base_url<U: IntoUrl>(base_url: U) -> Result<Self> {
+        Ok(Self {
+            http: reqwest::Client::new(),
+            base_url: base_url.into_url()?,
+        })
+    }
+
+    pub async fn network_info(&self) -> Result<NetworkInfo> {
+        self.get("network_info").await
+    }
+
+    /// Subscribe to the node's event stream.
+    pub async fn events(&self) -> Result<EventStream> {
+        let resp = self
+            .http
+            .get(self.url("events"))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(EventStream {
+            resp,
+            buf: String::new(),
+        })
+    }
+
+    /// Attempt a direct connection with `peer_id`, outcomes are reported as hole punches in
+    /// its peer info and events.
+    pub async fn connect_direct(&self, peer_id: PeerId) -> Result<()> {
+        self.http
+            .post(self.url("direct_connection"))
+            .json(&DirectConnection { peer_id })
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Shut the node down once its proxied sessions are done.
+    pub async fn shutdown(&self) -> Result<()> {
+        self.http
+            .post(self.url("shutdown"))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Reload the settings of the node, see [`crate::Reload`].
+    pub async fn reload(&self) -> Result<Reloaded> {
+        self.http
+            .post(self.url("reload"))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+    }
+
+    /// Log filter directives of the node.
+    pub async fn log_level(&self) -> Result<LogLevel> {
+        self.get("log_level").await
+    }
+
+    /// Change the log filter directives until the node is reloaded.
+    pub async fn set_log_level(&self, directives: String) -> Result<()> {
+        self.http
+            .put(self.url("log_level"))
+            .json(&LogLevel { directives })
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    pub async fn rendezvous(&self) -> Result<Vec<Rendezvous>> {
+        self.get("rendezvous").await
+    }
+
+    pub async fn add_rendezvous(&self, multiaddr: Multiaddr) -> Result<Rendezvous> {
+        self.post("rendezvous", &Rendezvous { id: 0, multiaddr })
+            .await
+    }
+
+    pub async fn del_rendezvous(&self, id: i64) -> Result<()> {
+        self.delete("rendezvous", id).await
+    }
+
+    pub async fn provided_services(&self) -> Result<Vec<ProvideService>> {
+        self.get("provide_service").await
+    }
+
+    /// Add a provided service, its `id` is ignored and assigned by the node.
+    pub async fn add_provided_service(&self, service: &ProvideService) -> Result<ProvideService> {
+        self.post("provide_service", service).await
+    }
+
+    pub async fn del_provided_service(&self, id: i64) -> Result<()> {
+        self.delete("provide_service", id).await
+    }
+
+    pub async fn used_services(&self) -> Result<Vec<UseService>> {
+        self.get("use_service").await
+    }
+
+    /// Add a used service, its `id` is ignored and assigned by the node.
+    pub async fn add_used_service(&self, service: &UseService) -> Result<UseService> {
+        self.post("use_service", service).await
+    }
+
+    pub async fn del_used_service(&self, id: i64) -> Result<()> {
+        self.delete("use_service", id).await
+    }
+
+    pub async fn address_book(&self) -> Result<Vec<PeerAddress>> {
+        self.get("address_book").await
+    }
+
+    /// Pin a static address for a peer, its `id` is ignored and assigned by the node.
+    pub async fn add_peer_address(&self, peer_address: &PeerAddress) -> Result<PeerAddress> {
+        self.post("address_book", peer_address).await
+    }
+
+    pub async fn del_peer_address(&self, id: i64) -> Result<()> {
+        self.delete("address_book", id).await
+    }
+
+    pub async fn contacts(&self) -> Result<Vec<Contact>> {
+        self.get("contacts").await
+    }
+
+    /// Add an alias for a peer, its `id` is ignored and assigned by the node.
+    pub async fn add_contact(&self, contact: &Contact) -> Result<Contact> {
+        self.post("contacts", contact).await
+    }
+
+    pub async fn del_contact(&self, id: i64) -> Result<()> {
+        self.delete("contacts", id).await
+    }
+
+    pub async fn namespaces(&self) -> Result<Vec<Namespace>> {
+        self.get("namespaces").await
+    }
+
+    /// Join a private rendezvous namespace, leaving the global one if it is the first.
+    pub async fn add_namespace(&self, name: String) -> Result<Namespace> {
+        self.post("namespaces", &Namespace { id: 0, name }).await
+    }
+
+    pub async fn del_namespace(&self, id: i64) -> Result<()> {
+        self.delete("namespaces", id).await
+    }
+
+    /// Sessions the node is proxying, oldest first.
+    pub async fn sessions(&self) -> Result<Vec<SessionInfo>> {
+        self.get("sessions").await
+    }
+
+    /// Metrics of the node in the OpenMetrics text format.
+    pub async fn metrics(&self) -> Result<String> {
+        self.http
+            .get(self.url("metrics"))
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await
+    }
+
+    /// The OpenAPI document describing the node's API.
+    pub async fn openapi(&self) -> Result<serde_json::Value> {
+        self.get("openapi.json").await
+    }
+
+    fn url(&self, path: &str) -> Url {
+        self.base_url.join(path).expect("api path should join")
+    }
+
+    async fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T> {
+        self.http
+            .get(self.url(path))
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+    }
+
+    async fn post<B: Serialize, T: DeserializeOwned>(&self, path: &str, body: &B) -> Result<T> {
+        self.http
+            .post(self.url(path))
+            .json(body)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+    }
+
+    async fn delete(&self, path: &str, id: i64) -> Result<()> {
+        self.http
+            .delete(self.url(path))
+            .query(&[("id", id)])
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+/// Server-sent events received from `/events`.
+pub struct EventStream {
+    resp: reqwest::Response,
+    buf: String,
+}
+
+impl EventStream {
+    /// The next event, or `None` once the node closed the stream. Events this client does not
+    /// understand, e.g. from a newer node, are skipped.
+    pub async fn next(&mut self) -> Result<Option<Event>> {
+        loop {
+            // Events are separated by a blank line
+            while let Some(end) = self.buf.find("\n\n") {
+                let block: String = self.buf.drain(..end + 2).collect();
+                let data = block
+                    .lines()
+                    .filter_map(|line| line.strip_prefix("data:"))
+                    .map(str::trim_start)
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                if data.is_empty() {
+                    // keep-alive comment
+                    continue;
+                }
+                match serde_json::from_str(&data) {
+                    Ok(event) => return Ok(Some(event)),
+                    Err(error) => tracing::warn!(?error, %data, "skipping unknown event"),
+                }
+            }
+            let Some(chunk) = self.resp.chunk().await? else {
+                return Ok(None);
+            };
+            self.buf.push_str(&String::from_utf8_lossy(&chunk));
+        }
+    }
+}
@@ -1,36 +1,263 @@
 use libp2p::{Multiaddr, PeerId};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use utoipa::ToSchema;
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
 pub struct Rendezvous {
     pub id: i64,
+    #[schema(value_type = String)]
     pub multiaddr: Multiaddr,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// A rendezvous namespace we register and discover peers under, e.g. one per team.
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug)]
+pub struct Namespace {
+    pub id: i64,
+    pub name: String,
+}
+
+/// A static address pinned for a peer in the address book.
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug)]
+pub struct PeerAddress {
+    pub id: i64,
+    #[schema(value_type = String)]
+    pub peer_id: PeerId,
+    #[schema(value_type = String)]
+    pub multiaddr: Multiaddr,
+}
+
+/// A human friendly alias for a peer, usable wherever a PeerId is accepted by the API.
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug)]
+pub struct Contact {
+    pub id: i64,
+    pub alias: String,
+    #[schema(value_type = String)]
+    pub peer_id: PeerId,
+    #[serde(default)]
+    pub note: String,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
 pub struct PeerInfo {
+    #[schema(value_type = String)]
     pub peer_id: PeerId,
+    /// Alias of the peer in the contacts
+    pub alias: Option<String>,
     pub connected: bool,
+    /// Addresses learned from identify and rendezvous discovery
+    #[schema(value_type = Vec<String>)]
+    pub addresses: Vec<Multiaddr>,
+    pub connections: Vec<ConnectionInfo>,
+    /// Round trip time of the last successful ping
+    pub rtt_ms: Option<f64>,
+    pub agent_version: Option<String>,
+    pub protocols: Vec<String>,
+    /// Unix timestamps in seconds
+    pub first_seen: u64,
+    pub last_seen: u64,
+    /// Rendezvous points this peer was discovered at
+    #[schema(value_type = Vec<String>)]
+    pub discovered_at: Vec<PeerId>,
+    /// Rendezvous namespaces this peer was discovered under
+    pub namespaces: Vec<String>,
+    /// Announced on the local network via mDNS
+    pub mdns: bool,
+    pub hole_punch: HolePunchStats,
+}
+
+/// Outcomes of DCUtR hole punches upgrading relayed connections with a peer to direct ones.
+#[derive(Serialize, Deserialize, ToSchema, Clone, Default, Debug)]
+pub struct HolePunchStats {
+    pub successes: u32,
+    pub failures: u32,
+    /// Error of the last failed hole punch
+    pub last_error: Option<String>,
+    /// Unix timestamp in seconds of the last hole punch
+    pub last_attempt: Option<u64>,
+}
+
+/// A peer to attempt a direct connection with.
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug)]
+pub struct DirectConnection {
+    #[schema(value_type = String)]
+    pub peer_id: PeerId,
+}
+
+/// A peer remembered across restarts.
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug)]
+pub struct KnownPeer {
+    #[schema(value_type = String)]
+    pub peer_id: PeerId,
+    #[schema(value_type = Vec<String>)]
+    pub addresses: Vec<Multiaddr>,
+    /// Unix timestamps in seconds
+    pub first_seen: u64,
+    pub last_seen: u64,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug)]
+pub struct ConnectionInfo {
+    pub direction: Direction,
+    #[schema(value_type = String)]
+    pub remote_address: Multiaddr,
+    pub relayed: bool,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, ToSchema, Debug)]
 pub struct NetworkInfo {
+    #[schema(value_type = String)]
     pub peer_id: PeerId,
+    pub reachability: Reachability,
+    /// Addresses we listen on
+    #[schema(value_type = Vec<String>)]
+    pub listen_addrs: Vec<Multiaddr>,
     pub peers: Vec<PeerInfo>,
+    /// Peers grouped by the rendezvous namespace they were discovered under
+    #[schema(value_type = BTreeMap<String, Vec<String>>)]
+    pub namespaces: BTreeMap<String, Vec<PeerId>>,
+    pub relays: Vec<RelayStatus>,
+}
+
+/// Whether other peers can dial us directly, as found by AutoNAT.
+#[derive(Serialize, Deserialize, ToSchema, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Reachability {
+    Public,
+    /// Behind NAT, we are only reachable through relays
+    Private,
+    Unknown,
+}
+
+/// A relay known to the node and how well reserving circuits on it went.
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug)]
+pub struct RelayStatus {
+    #[schema(value_type = String)]
+    pub peer_id: PeerId,
+    pub state: RelayState,
+    pub rtt_ms: Option<f64>,
+    /// Number of accepted and failed reservations
+    pub accepted: u32,
+    pub failed: u32,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, ToSchema, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum RelayState {
+    /// Known but not used, possibly waiting to retry after a failure
+    Candidate,
+    Reserving,
+    Reserved,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug)]
 pub struct UseService {
     pub id: i64,
+    #[schema(value_type = String)]
     pub peer_id: PeerId,
     pub host: String,
     pub port: u16,
     pub forwarder_port: u16,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug)]
 pub struct ProvideService {
     pub id: i64,
     pub host: String,
     pub port: u16,
 }
+
+#[derive(Serialize, Deserialize, ToSchema, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Inbound,
+    Outbound,
+}
+
+/// A session being proxied between a local connection and a peer.
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug)]
+pub struct SessionInfo {
+    pub id: u64,
+    #[schema(value_type = String)]
+    pub peer_id: PeerId,
+    pub direction: Direction,
+    /// Proxied over a relayed rather than a direct connection. Unknown for inbound sessions
+    /// while the peer is connected both directly and through a relay, as the remote picks the
+    /// connection.
+    pub relayed: Option<bool>,
+    pub host: String,
+    pub port: u16,
+    /// Unix timestamp in seconds
+    pub opened_at: u64,
+}
+
+/// Log filter directives, e.g. `info,preers=debug`.
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug)]
+pub struct LogLevel {
+    pub directives: String,
+}
+
+/// Outcome of reloading the settings of a running node.
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug, Default)]
+pub struct Reloaded {
+    /// Changed settings that only apply after a restart
+    pub needs_restart: Vec<String>,
+}
+
+/// Notable things happening in a node, streamed at `/events`.
+#[derive(Serialize, Deserialize, ToSchema, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    PeerConnected {
+        #[schema(value_type = String)]
+        peer_id: PeerId,
+    },
+    PeerDisconnected {
+        #[schema(value_type = String)]
+        peer_id: PeerId,
+    },
+    Registered {
+        #[schema(value_type = String)]
+        rendezvous_point: PeerId,
+        namespace: String,
+    },
+    ReservationAccepted {
+        #[schema(value_type = String)]
+        relay: PeerId,
+    },
+    ReachabilityChanged {
+        reachability: Reachability,
+    },
+    HolePunch {
+        #[schema(value_type = String)]
+        peer_id: PeerId,
+        success: bool,
+    },
+    SessionOpened {
+        #[schema(value_type = String)]
+        peer_id: PeerId,
+        direction: Direction,
+        /// Proxied over a relayed rather than a direct connection, see [`SessionInfo::relayed`]
+        relayed: Option<bool>,
+        host: String,
+        port: u16,
+    },
+    SessionClosed {
+        #[schema(value_type = String)]
+        peer_id: PeerId,
+        direction: Direction,
+        relayed: Option<bool>,
+        host: String,
+        port: u16,
+        local_to_remote: u64,
+        remote_to_local: u64,
+    },
+    SessionFailed {
+        #[schema(value_type = String)]
+        peer_id: PeerId,
+        direction: Direction,
+        host: String,
+        port: u16,
+        error: String,
+    },
+}
@@ -0,0 +1,290 @@
+//! A stream behaviour opening streams on direct connections when there are any.
+//!
+//! `libp2p_stream` opens a stream on any connection to the peer, which may be the relayed one
+//! even after a hole punch succeeded. Relayed connections are hidden from it while the peer has
+//! a direct connection, and shown again once the last direct connection closes. Streams already
+//! open on a hidden connection are not affected.
+
+use libp2p::{
+    core::{transport::PortUse, ConnectedPoint, Endpoint},
+    swarm::{
+        behaviour::{ConnectionClosed, ConnectionEstablished},
+        ConnectionDenied, ConnectionId, FromSwarm, NetworkBehaviour, THandler, THandlerInEvent,
+        THandlerOutEvent, ToSwarm,
+    },
+    Multiaddr, PeerId,
+};
+use libp2p_stream as stream;
+use std::collections::{HashMap, HashSet};
+use std::task::{Context, Poll};
+
+#[derive(Default)]
+pub(crate) struct Behaviour {
+    inner: stream::Behaviour,
+    connections: HashMap<ConnectionId, (PeerId, ConnectedPoint)>,
+    // relayed connections the inner behaviour was told are closed
+    hidden: HashSet<ConnectionId>,
+}
+
+impl Behaviour {
+    pub(crate) fn new_control(&self) -> stream::Control {
+        self.inner.new_control()
+    }
+
+    fn has_direct(&self, peer_id: &PeerId) -> bool {
+        self.connections
+            .values()
+            .any(|(peer, endpoint)| peer == peer_id && !endpoint.is_relayed())
+    }
+
+    fn relayed(&self, peer_id: &PeerId) -> Vec<ConnectionId> {
+        self.connections
+            .iter()
+            .filter(|(_, (peer, endpoint))| peer == peer_id && endpoint.is_relayed())
+            .map(|(connection_id, _)| *connection_id)
+            .collect()
+    }
+
+    fn hide(&mut self, connection_id: ConnectionId) {
+        let Some((peer_id, endpoint)) = self.connections.get(&connection_id) else {
+            return;
+        };
+        if self.hidden.insert(connection_id) {
+            self.inner.on_swarm_event(FromSwarm::ConnectionClosed(ConnectionClosed {
+                peer_id: *peer_id,
+                connection_id,
+                endpoint,
+                cause: None,
+                remaining_established: 0,
+            }));
+        }
+    }
+
+    fn show(&mut self, connection_id: ConnectionId) {
+        let Some((peer_id, endpoint)) = self.connections.get(&connection_id) else {
+            return;
+        };
+        if self.hidden.remove(&connection_id) {
+            self.inner
+                .on_swarm_event(FromSwarm::ConnectionEstablished(ConnectionEstablished {
+                    peer_id: *peer_id,
+                    connection_id,
+                    endpoint,
+                    failed_addresses: &[],
+                    other_established: 0,
+                }));
+        }
+    }
+}
+
+impl NetworkBehaviour for Behaviour {
+    type ConnectionHandler = THandler<stream::Behaviour>;
+    type ToSwarm = <stream::Behaviour as NetworkBehaviour>::ToSwarm;
+
+    fn handle_pending_inbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        local_addr: &Multiaddr,
+        remote_addr: &Multiaddr,
+    ) -> Result<(), ConnectionDenied> {
+        self.inner
+            .handle_pending_inbound_connection(connection_id, local_addr, remote_addr)
+    }
+
+    fn handle_established_inbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        local_addr: &Multiaddr,
+        remote_addr: &Multiaddr,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        self.inner
+            .handle_established_inbound_connection(connection_id, peer, local_addr, remote_addr)
+    }
+
+    fn handle_pending_outbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        maybe_peer: Option<PeerId>,
+        addresses: &[Multiaddr],
+        effective_role: Endpoint,
+    ) -> Result<Vec<Multiaddr>, ConnectionDenied> {
+        self.inner.handle_pending_outbound_connection(
+            connection_id,
+            maybe_peer,
+            addresses,
+            effective_role,
+        )
+    }
+
+    fn handle_established_outbound_connection(
+        &mut self,
+        connection_id: ConnectionId,
+        peer: PeerId,
+        addr: &Multiaddr,
+        role_override: Endpoint,
+        port_use: PortUse,
+    ) -> Result<THandler<Self>, ConnectionDenied> {
+        self.inner.handle_established_outbound_connection(
+            connection_id,
+            peer,
+            addr,
+            role_override,
+            port_use,
+        )
+    }
+
+    fn on_swarm_event(&mut self, event: FromSwarm) {
+        match event {
+            FromSwarm::ConnectionEstablished(ConnectionEstablished {
+                peer_id,
+                connection_id,
+                endpoint,
+                ..
+            }) => {
+                let had_direct = self.has_direct(&peer_id);
+                self.connections
+                    .insert(connection_id, (peer_id, endpoint.clone()));
+                self.inner.on_swarm_event(event);
+                if endpoint.is_relayed() {
+                    if had_direct {
+                        self.hide(connection_id);
+                    }
+                } else {
+                    for relayed in self.relayed(&peer_id) {
+                        self.hide(relayed);
+                    }
+                }
+            }
+            FromSwarm::ConnectionClosed(ConnectionClosed {
+                peer_id,
+                connection_id,
+                ..
+            }) => {
+                if !self.hidden.remove(&connection_id) {
+                    self.inner.on_swarm_event(event);
+                }
+                self.connections.remove(&connection_id);
+                if !self.has_direct(&peer_id) {
+                    for relayed in self.relayed(&peer_id) {
+                        self.show(relayed);
+                    }
+                }
+            }
+            _ => self.inner.on_swarm_event(event),
+        }
+    }
+
+    fn on_connection_handler_event(
+        &mut self,
+        peer_id: PeerId,
+        connection_id: ConnectionId,
+        event: THandlerOutEvent<Self>,
+    ) {
+        self.inner
+            .on_connection_handler_event(peer_id, connection_id, event)
+    }
+
+    fn poll(&mut self, cx: &mut Context<'_>) -> Poll<ToSwarm<Self::ToSwarm, THandlerInEvent<Self>>> {
+        self.inner.poll(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{task::noop_waker_ref, FutureExt};
+    use std::future::Future;
+    use libp2p::{
+        swarm::{ConnectionHandler, ConnectionHandlerEvent},
+        StreamProtocol,
+    };
+
+    const PROTOCOL: StreamProtocol = StreamProtocol::new("/preers/test");
+
+    // establishes an outbound connection the way the swarm would and returns its handler
+    fn connect(
+        behaviour: &mut Behaviour,
+        peer_id: PeerId,
+        id: usize,
+        address: &str,
+    ) -> (ConnectionId, THandler<Behaviour>) {
+        let connection_id = ConnectionId::new_unchecked(id);
+        let address: Multiaddr = address.parse().unwrap();
+        let handler = behaviour
+            .handle_established_outbound_connection(
+                connection_id,
+                peer_id,
+                &address,
+                Endpoint::Dialer,
+                PortUse::Reuse,
+            )
+            .unwrap();
+        let endpoint = ConnectedPoint::Dialer {
+            address,
+            role_override: Endpoint::Dialer,
+            port_use: PortUse::Reuse,
+        };
+        behaviour.on_swarm_event(FromSwarm::ConnectionEstablished(ConnectionEstablished {
+            peer_id,
+            connection_id,
+            endpoint: &endpoint,
+            failed_addresses: &[],
+            other_established: 0,
+        }));
+        (connection_id, handler)
+    }
+
+    fn disconnect(behaviour: &mut Behaviour, peer_id: PeerId, connection_id: ConnectionId) {
+        let endpoint = behaviour.connections[&connection_id].1.clone();
+        behaviour.on_swarm_event(FromSwarm::ConnectionClosed(ConnectionClosed {
+            peer_id,
+            connection_id,
+            endpoint: &endpoint,
+            cause: None,
+            remaining_established: 0,
+        }));
+    }
+
+    fn open_stream(control: &stream::Control, peer_id: PeerId) -> impl Future + Unpin {
+        let mut control = control.clone();
+        let mut open = async move { control.open_stream(peer_id, PROTOCOL).await }.boxed();
+        let mut cx = Context::from_waker(noop_waker_ref());
+        assert!(open.poll_unpin(&mut cx).is_pending());
+        open
+    }
+
+    // whether the connection was asked to open a stream
+    fn requested(handler: &mut THandler<Behaviour>) -> bool {
+        let mut cx = Context::from_waker(noop_waker_ref());
+        matches!(
+            handler.poll(&mut cx),
+            Poll::Ready(ConnectionHandlerEvent::OutboundSubstreamRequest { .. })
+        )
+    }
+
+    #[test]
+    fn streams_prefer_direct_connections() {
+        let mut behaviour = Behaviour::default();
+        let control = behaviour.new_control();
+        let peer_id = PeerId::random();
+        let relay_id = PeerId::random();
+        let (_, mut relayed) = connect(
+            &mut behaviour,
+            peer_id,
+            1,
+            &format!("/memory/1/p2p/{relay_id}/p2p-circuit/p2p/{peer_id}"),
+        );
+        let (direct_id, mut direct) = connect(&mut behaviour, peer_id, 2, "/memory/2");
+
+        // the inner behaviour picks a random connection, so open a few streams
+        let _pending: Vec<_> = (0..8).map(|_| open_stream(&control, peer_id)).collect();
+        assert!(requested(&mut direct));
+        assert!(!requested(&mut relayed));
+
+        // the relayed connection is offered again once the direct one closes
+        disconnect(&mut behaviour, peer_id, direct_id);
+        let _pending = open_stream(&control, peer_id);
+        assert!(requested(&mut relayed));
+    }
+}
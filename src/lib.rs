@@ -1,3 +1,21 @@
+mod address_book;
+pub mod client;
 pub mod data;
+mod direct_stream;
+pub mod network;
+pub mod proto;
+pub mod proxy;
+mod relay_manager;
+mod relay_server;
+mod transport;
+
+pub use network::{Command, Network, NetworkConfig, Reload};
+pub use relay_server::RelayConfig;
+pub use transport::{TlsCertificate, TransportConfig};
+
+use tokio::sync::oneshot;
+
+pub type Responder<T> = oneshot::Sender<T>;
 
 pub const DEFAULT_HTTP_PORT: u16 = 9843;
+pub const MPSC_CHANNEL_SIZE: usize = 256;
@@ -0,0 +1,1697 @@
+use libp2p::{
+    autonat,
+    core::{multiaddr::Protocol, transport::ListenerId, ConnectedPoint},
+    dcutr,
+    futures::StreamExt,
+    identify,
+    identity::Keypair,
+    kad, mdns,
+    metrics::{Metrics, Recorder},
+    noise, ping, relay,
+    rendezvous::{self, Cookie, Namespace},
+    swarm::{
+        behaviour::toggle::Toggle,
+        dial_opts::{DialOpts, PeerCondition},
+        ConnectionId, DialError, NetworkBehaviour, Swarm, SwarmEvent,
+    },
+    yamux, Multiaddr, PeerId, StreamProtocol, SwarmBuilder,
+};
+
+use prometheus_client::{
+    encoding::EncodeLabelSet,
+    metrics::{counter::Counter, family::Family},
+    registry::Registry,
+};
+
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::data::{
+    ConnectionInfo, Direction, Event, HolePunchStats, KnownPeer, NetworkInfo, PeerAddress,
+    PeerInfo, ProvideService, Reachability, Reloaded, SessionInfo, UseService,
+};
+use crate::relay_manager::RelayManager;
+use crate::relay_server::{RelayConfig, RelayMetrics, RelayPolicy};
+use crate::transport::TransportConfig;
+use crate::{address_book, direct_stream, proxy, Responder, MPSC_CHANNEL_SIZE};
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::task::JoinHandle;
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+
+// default rendezvous registration ttl is 2 hours
+const DEFAULT_RDV_REGISTRATION_TTL: Duration = Duration::from_secs(2 * 60 * 60);
+
+// namespace used when none is configured, shared by every preers node
+const DEFAULT_NAMESPACE: &str = "preers";
+
+// default time interval for rendezvous registration renewal and discovery
+const DEFAULT_RDV_REFRESH: Duration = Duration::from_secs(5 * 60);
+
+// time given to rendezvous unregistrations to go out on shutdown, they get no response
+const UNREGISTER_DELAY: Duration = Duration::from_millis(500);
+
+/// Disconnected peers are forgotten after not being seen this long, also by the node's
+/// database.
+pub const PEER_EXPIRY: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+// addresses kept per peer, the most recently learned ones
+const MAX_PEER_ADDRESSES: usize = 32;
+
+// a direct connection attempt without a hole punch outcome by then may be made again
+const DIRECT_ATTEMPT_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Kademlia protocol of the preers DHT, kept apart from the public IPFS DHT
+const KAD_PROTOCOL: StreamProtocol = StreamProtocol::new("/preers/kad/1.0.0");
+
+// DHT key relays provide, so that peers can find relays without a rendezvous point
+const KAD_RELAY_KEY: &[u8] = b"/preers/relay";
+
+#[derive(NetworkBehaviour)]
+struct Behaviour {
+    identify: identify::Behaviour,
+    dcutr: dcutr::Behaviour,
+    relay_client: relay::client::Behaviour,
+    rendezvous_client: rendezvous::client::Behaviour,
+    ping: ping::Behaviour,
+    autonat: autonat::Behaviour,
+    stream: direct_stream::Behaviour,
+    relay: Toggle<relay::Behaviour>,
+    rendezvous: Toggle<rendezvous::server::Behaviour>,
+    address_book: address_book::Behaviour,
+    mdns: Toggle<mdns::tokio::Behaviour>,
+    kad: Toggle<kad::Behaviour<kad::store::MemoryStore>>,
+}
+
+// What we know about a peer, reported as `PeerInfo`
+struct PeerRecord {
+    // oldest first, see `PeerRecord::add_addresses`
+    addresses: VecDeque<Multiaddr>,
+    connections: HashMap<ConnectionId, ConnectionInfo>,
+    rtt: Option<Duration>,
+    agent_version: Option<String>,
+    protocols: Vec<String>,
+    first_seen: SystemTime,
+    // last connection, identify, ping or discovery, see `Network::seen`
+    last_seen: SystemTime,
+    // rendezvous points the peer was discovered at
+    discovered_at: HashSet<PeerId>,
+    // rendezvous namespaces the peer was discovered under
+    namespaces: HashSet<String>,
+    // currently announced on the local network
+    mdns: bool,
+    hole_punch: HolePunchStats,
+}
+
+impl PeerRecord {
+    // Remember `addresses` as the most recent ones, forgetting the oldest beyond
+    // MAX_PEER_ADDRESSES
+    fn add_addresses(&mut self, addresses: impl IntoIterator<Item = Multiaddr>) {
+        for address in addresses {
+            self.addresses.retain(|known| *known != address);
+            self.addresses.push_back(address);
+        }
+        let excess = self.addresses.len().saturating_sub(MAX_PEER_ADDRESSES);
+        self.addresses.drain(..excess);
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct HolePunchLabels {
+    result: &'static str,
+}
+
+/// Requests handled by a running [`Network`], sent through [`Network::handle`].
+pub enum Command {
+    AddRendezvous(Multiaddr),
+    TalkToRendezvous(PeerId),
+    GetNetworkInfo(Responder<NetworkInfo>),
+    /// Metrics in the Prometheus text format
+    GetMetrics(Responder<String>),
+    /// Sessions being proxied
+    GetSessions(Responder<Vec<SessionInfo>>),
+    UseService(UseService),
+    /// Stop the local listener of the used service on this forwarder port
+    StopUsingService(u16),
+    ProvideService(ProvideService),
+    StopProvidingService(ProvideService),
+    AddPeerAddress(PeerAddress),
+    RemovePeerAddress(PeerAddress),
+    AddNamespace(String),
+    RemoveNamespace(String),
+    /// Attempt a direct connection with a peer, false if it can't be dialed
+    ConnectDirect(PeerId, Responder<bool>),
+    /// Stop accepting proxy connections, drain sessions and unregister from rendezvous
+    /// points, then return from [`Network::run`]
+    Shutdown,
+    /// Apply changed settings, see [`Reload`]
+    Reload(Box<Reload>, Responder<Reloaded>),
+}
+
+/// Settings of a running [`Network`] that can be changed without a restart.
+///
+/// Listen addresses can be changed but not the enabled transports, and the relay allow list
+/// and rate limit but not the other relay limits. Used services whose settings changed are
+/// restarted, sessions already proxied are kept.
+pub struct Reload {
+    pub transport: TransportConfig,
+    pub relay: Option<RelayConfig>,
+    pub max_relays: usize,
+    pub direct_wait: Duration,
+    pub close_relayed: bool,
+    pub drain_timeout: Duration,
+    pub used_services: Vec<UseService>,
+    pub provided_services: Vec<ProvideService>,
+}
+
+/// The libp2p side of a preers node: swarm, rendezvous/relay bookkeeping and proxy tasks.
+pub struct Network {
+    swarm: Swarm<Behaviour>,
+    rendezvous_list: Vec<Multiaddr>,
+    rendezvous_points: HashSet<PeerId>,
+    relays: RelayManager,
+    is_relay: bool,
+    relay_config: Option<RelayConfig>,
+    relay_policy: RelayPolicy,
+    transport: TransportConfig,
+    listen_addrs: Vec<Multiaddr>,
+    listeners: HashMap<Multiaddr, ListenerId>,
+    // as found by AutoNAT, relays are only used when private
+    reachability: Reachability,
+    pending_relay_connections: HashSet<ConnectionId>,
+    pending_rendezvous_connections: HashSet<ConnectionId>,
+    // configured rendezvous namespaces, see `Network::namespaces`
+    namespaces: Vec<Namespace>,
+    // rendezvous request cookies
+    rdv_cookies: HashMap<(PeerId, Option<Namespace>), Cookie>,
+    // peers we ever connected to or discovered
+    peers: HashMap<PeerId, PeerRecord>,
+    // channel to handle provide service requests
+    provided_services: proxy::ProvidedServices,
+    // local listeners of used services by forwarder port
+    used_services: HashMap<u16, (UseService, JoinHandle<()>)>,
+    // event bus for subscribers such as the `/events` endpoint
+    events: broadcast::Sender<Event>,
+    // peers whose addresses changed, to be persisted, see `Network::known_peers`
+    known_peers: Option<mpsc::UnboundedSender<KnownPeer>>,
+    registry: Registry,
+    metrics: Metrics,
+    relay_metrics: RelayMetrics,
+    hole_punches: Family<HolePunchLabels, Counter>,
+    // peers we have a direct or a relayed connection with, watched by proxy tasks
+    direct_peers: watch::Sender<HashSet<PeerId>>,
+    relayed_peers: watch::Sender<HashSet<PeerId>>,
+    // how long used services wait for a direct connection before proxying over a relay
+    direct_wait: Duration,
+    // close relayed connections to peers we have a direct connection with
+    close_relayed: bool,
+    // direct connection attempts requested by proxy tasks
+    connect_direct_tx: mpsc::Sender<PeerId>,
+    connect_direct_rx: mpsc::Receiver<PeerId>,
+    // direct connection attempts awaiting a hole punch, by when they were made
+    direct_attempts: HashMap<PeerId, Instant>,
+    // proxied sessions, drained for up to `drain_timeout` on shutdown
+    sessions: TaskTracker,
+    active_sessions: proxy::ActiveSessions,
+    shutdown: CancellationToken,
+    drain_timeout: Duration,
+    // commands from handles, and from our own tasks for periodic work
+    app_tx: mpsc::Sender<Command>,
+    app_rx: mpsc::Receiver<Command>,
+}
+
+/// Settings of a [`Network`], see [`Network::new`].
+#[derive(Clone, Debug)]
+pub struct NetworkConfig {
+    /// Transports we dial and listen with, and where we listen
+    pub transport: TransportConfig,
+    /// Serve as a relay with these limits
+    pub relay: Option<RelayConfig>,
+    /// Serve as a rendezvous point
+    pub rendezvous: bool,
+    /// Discover peers on the local network
+    pub mdns: bool,
+    /// Find peers and relays through the Kademlia DHT, when rendezvous points are unreachable
+    pub kad: bool,
+    /// Circuit reservations are kept on at most this many of the best known relays
+    pub max_relays: usize,
+    /// How long used services wait for a hole punch before proxying over a relay
+    pub direct_wait: Duration,
+    /// Close relayed connections to peers we have a direct connection with
+    pub close_relayed: bool,
+    /// How long proxied sessions get to finish on shutdown before being closed
+    pub drain_timeout: Duration,
+    /// Rendezvous points dialed once [`Network::init`] is called
+    pub rendezvous_list: Vec<Multiaddr>,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            transport: Default::default(),
+            relay: None,
+            rendezvous: false,
+            mdns: false,
+            kad: false,
+            max_relays: 2,
+            direct_wait: Duration::ZERO,
+            close_relayed: false,
+            drain_timeout: Duration::from_secs(10),
+            rendezvous_list: Default::default(),
+        }
+    }
+}
+
+impl Network {
+    /// Build the swarm. Proxy streams are opened on direct connections when there are any.
+    pub fn new(keypair: Keypair, config: NetworkConfig) -> Result<Self, Box<dyn Error>> {
+        let NetworkConfig {
+            transport,
+            relay,
+            rendezvous: is_rendezvous,
+            mdns: is_mdns,
+            kad: is_kad,
+            max_relays,
+            direct_wait,
+            close_relayed,
+            drain_timeout,
+            rendezvous_list,
+        } = config;
+        let peer_id = keypair.public().to_peer_id();
+        let is_relay = relay.is_some();
+        let relay_policy = RelayPolicy::default();
+        let mut registry = Registry::default();
+        let mdns = if is_mdns {
+            Some(mdns::tokio::Behaviour::new(mdns::Config::default(), peer_id)?)
+        } else {
+            None
+        };
+        let listen_addrs = transport.listen_addrs()?;
+        if let Some(psk) = transport.psk {
+            tracing::info!(fingerprint = %psk.fingerprint(), "private network");
+        }
+        let swarm = SwarmBuilder::with_existing_identity(keypair)
+            .with_tokio()
+            .with_other_transport(|keypair| transport.build(keypair))?
+            .with_dns()?
+            .with_relay_client(noise::Config::new, yamux::Config::default)?
+            .with_bandwidth_metrics(&mut registry)
+            .with_behaviour(|keypair, relay_behaviour| Behaviour {
+                identify: identify::Behaviour::new(identify::Config::new(
+                    "/preers/id/1.0.0".to_string(),
+                    keypair.public(),
+                )),
+                dcutr: dcutr::Behaviour::new(peer_id),
+                relay_client: relay_behaviour,
+                rendezvous_client: rendezvous::client::Behaviour::new(keypair.clone()),
+                ping: ping::Behaviour::new(ping::Config::new()),
+                autonat: autonat::Behaviour::new(peer_id, autonat::Config::default()),
+                stream: Default::default(),
+                relay: relay
+                    .as_ref()
+                    .map(|config| relay::Behaviour::new(peer_id, config.relay_config(&relay_policy)))
+                    .into(),
+                rendezvous: (if is_rendezvous {
+                    Some(rendezvous::server::Behaviour::new(
+                        rendezvous::server::Config::default(),
+                    ))
+                } else {
+                    None
+                })
+                .into(),
+                address_book: Default::default(),
+                mdns: mdns.into(),
+                kad: (if is_kad {
+                    Some(kad::Behaviour::with_config(
+                        peer_id,
+                        kad::store::MemoryStore::new(peer_id),
+                        kad::Config::new(KAD_PROTOCOL),
+                    ))
+                } else {
+                    None
+                })
+                .into(),
+            })?
+            .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(2 * 60 * 60)))
+            .build();
+
+        let provided_services = proxy::ProvidedServices::default();
+        let (events, _) = broadcast::channel(MPSC_CHANNEL_SIZE);
+        let (connect_direct_tx, connect_direct_rx) = mpsc::channel(MPSC_CHANNEL_SIZE);
+        let (app_tx, app_rx) = mpsc::channel(MPSC_CHANNEL_SIZE);
+        let direct_peers = watch::Sender::new(Default::default());
+        let relayed_peers = watch::Sender::new(Default::default());
+        let sessions = TaskTracker::new();
+        let active_sessions = proxy::ActiveSessions::new(events.clone());
+        let shutdown = CancellationToken::new();
+
+        // spawn provide services, handle incoming requests
+        tokio::spawn(proxy::provide_services(
+            provided_services.clone(),
+            swarm.behaviour().stream.new_control(),
+            active_sessions.clone(),
+            direct_peers.subscribe(),
+            relayed_peers.subscribe(),
+            sessions.clone(),
+            shutdown.clone(),
+        ));
+
+        let metrics = Metrics::new(&mut registry);
+        let preers_registry = registry.sub_registry_with_prefix("preers");
+        let relay_metrics = RelayMetrics::new(preers_registry);
+        let hole_punches = Family::default();
+        preers_registry.register(
+            "hole_punches",
+            "DCUtR hole punches",
+            hole_punches.clone(),
+        );
+
+        Ok(Self {
+            swarm,
+            rendezvous_list,
+            rendezvous_points: Default::default(),
+            relays: RelayManager::new(max_relays),
+            is_relay,
+            relay_config: relay,
+            relay_policy,
+            transport,
+            listen_addrs,
+            listeners: Default::default(),
+            reachability: Reachability::Unknown,
+            pending_relay_connections: Default::default(),
+            pending_rendezvous_connections: Default::default(),
+            namespaces: Default::default(),
+            rdv_cookies: Default::default(),
+            peers: Default::default(),
+            provided_services,
+            used_services: Default::default(),
+            events,
+            known_peers: None,
+            metrics,
+            relay_metrics,
+            hole_punches,
+            registry,
+            direct_peers,
+            relayed_peers,
+            direct_wait,
+            close_relayed,
+            connect_direct_tx,
+            connect_direct_rx,
+            direct_attempts: Default::default(),
+            sessions,
+            active_sessions,
+            shutdown,
+            drain_timeout,
+            app_tx,
+            app_rx,
+        })
+    }
+
+    /// Start listening, dial known rendezvous points and start the given services.
+    ///
+    /// We register and discover peers under `namespaces` at rendezvous points, or under the
+    /// global `preers` namespace if there are none.
+    pub fn init(
+        &mut self,
+        used_services: Vec<UseService>,
+        provided_services: Vec<ProvideService>,
+        known_peers: Vec<KnownPeer>,
+        namespaces: Vec<String>,
+        maybe_external_address: Option<Multiaddr>,
+    ) -> Result<(), Box<dyn Error>> {
+        for namespace in namespaces {
+            self.add_namespace(namespace);
+        }
+
+        for addr in self.listen_addrs.clone() {
+            tracing::info!(%addr, "listen on");
+            let listener_id = self.swarm.listen_on(addr.clone())?;
+            self.listeners.insert(addr, listener_id);
+        }
+
+        // seed addresses of peers remembered from previous runs, so used services can dial
+        // their providers before rendezvous discovery
+        for known_peer in known_peers {
+            for address in known_peer.addresses.iter() {
+                self.swarm
+                    .behaviour_mut()
+                    .address_book
+                    .add_address(known_peer.peer_id, address.clone());
+            }
+            let peer = self.peer_mut(known_peer.peer_id);
+            peer.add_addresses(known_peer.addresses);
+            peer.first_seen = UNIX_EPOCH + Duration::from_secs(known_peer.first_seen);
+            peer.last_seen = UNIX_EPOCH + Duration::from_secs(known_peer.last_seen);
+        }
+
+        // add known rendezvous
+        // TODO: work around the clone here
+        for rendezvous in self.rendezvous_list.clone().into_iter() {
+            self.add_rendezvous(&rendezvous);
+        }
+
+        // add used services
+        for use_service in used_services {
+            self.use_service(use_service);
+        }
+
+        if let Some(external_address) = maybe_external_address {
+            if self.check_transport(&external_address) {
+                self.swarm.add_external_address(external_address);
+            }
+        }
+
+        // add known provided services
+        for provide_service in provided_services {
+            self.provide_service(provide_service);
+        }
+        Ok(())
+    }
+
+    /// Allow peers to use `provide_service` through us.
+    pub fn provide_service(&mut self, provide_service: ProvideService) {
+        self.provided_services
+            .lock()
+            .unwrap()
+            .insert((provide_service.host, provide_service.port));
+    }
+
+    /// Refuse proxy streams to a service provided before, sessions already proxied are kept.
+    pub fn stop_providing_service(&mut self, provide_service: ProvideService) {
+        self.provided_services
+            .lock()
+            .unwrap()
+            .remove(&(provide_service.host, provide_service.port));
+    }
+
+    /// Register and discover peers under `namespace` from now on, instead of the global one.
+    pub fn add_namespace(&mut self, namespace: String) {
+        let Ok(namespace) = Namespace::new(namespace) else {
+            tracing::error!("rendezvous namespace too long");
+            return;
+        };
+        if self.namespaces.contains(&namespace) {
+            return;
+        }
+        let mut namespaces = self.namespaces.clone();
+        namespaces.push(namespace);
+        self.set_namespaces(namespaces);
+    }
+
+    /// Unregister from `namespace` at every rendezvous point.
+    pub fn remove_namespace(&mut self, namespace: String) {
+        let namespaces = self
+            .namespaces
+            .iter()
+            .filter(|ns| ns.to_string() != namespace)
+            .cloned()
+            .collect();
+        self.set_namespaces(namespaces);
+    }
+
+    /// Pin a static address for a peer, used when dialing it by peer id.
+    pub fn add_peer_address(&mut self, peer_address: PeerAddress) {
+        if !self.check_transport(&peer_address.multiaddr) {
+            return;
+        }
+        tracing::info!(peer_id = %peer_address.peer_id, multiaddr = %peer_address.multiaddr, "add static peer address");
+        self.swarm
+            .behaviour_mut()
+            .address_book
+            .add_address(peer_address.peer_id, peer_address.multiaddr.clone());
+        // static peers bootstrap the DHT
+        self.add_kad_address(peer_address.peer_id, peer_address.multiaddr.clone());
+        self.peer_mut(peer_address.peer_id)
+            .add_addresses([peer_address.multiaddr]);
+    }
+
+    /// Forget a static address pinned with [`Network::add_peer_address`].
+    pub fn remove_peer_address(&mut self, peer_address: PeerAddress) {
+        let PeerAddress { peer_id, multiaddr, .. } = peer_address;
+        tracing::info!(%peer_id, %multiaddr, "remove static peer address");
+        self.swarm
+            .behaviour_mut()
+            .address_book
+            .remove_address(&peer_id, &multiaddr);
+        if let Some(kad) = self.swarm.behaviour_mut().kad.as_mut() {
+            kad.remove_address(&peer_id, &multiaddr);
+        }
+        if let Some(peer) = self.peers.get_mut(&peer_id) {
+            peer.addresses.retain(|address| *address != multiaddr);
+        }
+    }
+
+    /// A handle for opening proxied streams to peers, see [`proxy::connect`].
+    pub fn control(&self) -> libp2p_stream::Control {
+        self.swarm.behaviour().stream.new_control()
+    }
+
+    /// The event bus, `subscribe` to it to receive [`Event`]s.
+    pub fn events(&self) -> broadcast::Sender<Event> {
+        self.events.clone()
+    }
+
+    /// A handle for sending [`Command`]s to the network once it runs.
+    pub fn handle(&self) -> mpsc::Sender<Command> {
+        self.app_tx.clone()
+    }
+
+    /// Peers as their known addresses change, for persisting them across restarts. Unlike on the
+    /// [`events`](Self::events) bus no update is dropped, so they should be received
+    /// continuously.
+    pub fn known_peers(&mut self) -> mpsc::UnboundedReceiver<KnownPeer> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.known_peers = Some(tx);
+        rx
+    }
+
+    /// Drive the network, handling swarm events and commands sent through handles.
+    pub async fn run(mut self) {
+        loop {
+            tokio::select! {
+                Some(command) = self.app_rx.recv() => {
+                    match command {
+                        Command::Shutdown => {
+                            self.shutdown().await;
+                            break;
+                        }
+                        Command::Reload(reload, resp) => {
+                            let _ = resp.send(self.reload(*reload).await);
+                        }
+                        command => self.handle_command(command),
+                    }
+                }
+                Some(peer_id) = self.connect_direct_rx.recv() => {
+                    self.connect_direct(peer_id);
+                }
+                // backed off relays are retried once due, even if nothing else happens
+                _ = sleep_until(self.relays.next_retry()) => {
+                    self.reserve_relays();
+                }
+                event = self.swarm.select_next_some() => {
+                    self.handle_event(event);
+                }
+                else => {
+                    break;
+                }
+            }
+        }
+    }
+
+    async fn reload(&mut self, reload: Reload) -> Reloaded {
+        let mut needs_restart = Vec::new();
+        if let Err(error) = self.set_transport(reload.transport) {
+            needs_restart.push(error);
+        }
+        match (&self.relay_config, reload.relay) {
+            (Some(config), Some(new)) => {
+                self.relay_policy.set(&new);
+                if !config.same_limits(&new) {
+                    needs_restart.push("relay limits other than the allow list and rate".into());
+                }
+            }
+            (None, None) => {}
+            _ => needs_restart.push("serving as a relay".into()),
+        }
+        let peers = &self.peers;
+        let released = self.relays.set_max_reservations(reload.max_relays, |relay| {
+            peers.get(relay).and_then(|peer| peer.rtt)
+        });
+        for listener_id in released {
+            self.swarm.remove_listener(listener_id);
+        }
+        self.reserve_relays();
+        self.close_relayed = reload.close_relayed;
+        self.drain_timeout = reload.drain_timeout;
+        let restart_used = self.direct_wait != reload.direct_wait;
+        self.direct_wait = reload.direct_wait;
+        self.set_used_services(reload.used_services, restart_used).await;
+        *self.provided_services.lock().unwrap() = reload
+            .provided_services
+            .into_iter()
+            .map(|ProvideService { host, port, .. }| (host, port))
+            .collect();
+        for setting in needs_restart.iter() {
+            tracing::warn!(%setting, "changed setting needs a restart");
+        }
+        tracing::info!("reloaded");
+        Reloaded { needs_restart }
+    }
+
+    // Listen on the addresses of `transport`, which must enable the same transports
+    fn set_transport(&mut self, transport: TransportConfig) -> Result<(), String> {
+        let transports = |t: &TransportConfig| {
+            let psk = t.psk.map(|psk| psk.fingerprint());
+            (t.tcp, t.is_quic(), t.ipv6, t.websocket, t.ws_tls.is_some(), psk)
+        };
+        if transports(&self.transport) != transports(&transport) {
+            return Err("enabled transports".into());
+        }
+        let listen_addrs = transport
+            .listen_addrs()
+            .map_err(|error| format!("listen addresses: {error}"))?;
+        for (addr, listener_id) in self.listeners.clone() {
+            if !listen_addrs.contains(&addr) {
+                tracing::info!(%addr, "stop listening on");
+                self.swarm.remove_listener(listener_id);
+                self.listeners.remove(&addr);
+            }
+        }
+        for addr in listen_addrs.iter() {
+            if self.listeners.contains_key(addr) {
+                continue;
+            }
+            tracing::info!(%addr, "listen on");
+            match self.swarm.listen_on(addr.clone()) {
+                Ok(listener_id) => {
+                    self.listeners.insert(addr.clone(), listener_id);
+                }
+                Err(error) => tracing::error!(%addr, ?error, "listen error"),
+            }
+        }
+        self.transport = transport;
+        self.listen_addrs = listen_addrs;
+        Ok(())
+    }
+
+    // Stop the local listeners of used services no longer used or changed, and start the new
+    // ones. Proxied sessions are kept.
+    async fn set_used_services(&mut self, used_services: Vec<UseService>, restart: bool) {
+        let is_same = |a: &UseService, b: &UseService| {
+            (a.peer_id, &a.host, a.port) == (b.peer_id, &b.host, b.port)
+        };
+        let stopped = self
+            .used_services
+            .iter()
+            .filter(|(forwarder_port, (use_service, handle))| {
+                restart
+                    || handle.is_finished()
+                    || !used_services.iter().any(|new| {
+                        new.forwarder_port == **forwarder_port && is_same(new, use_service)
+                    })
+            })
+            .map(|(forwarder_port, _)| *forwarder_port)
+            .collect::<Vec<_>>();
+        for forwarder_port in stopped {
+            if let Some((use_service, handle)) = self.used_services.remove(&forwarder_port) {
+                tracing::info!(?use_service, "stop using service");
+                handle.abort();
+                // the forwarder port is free again once the task is gone
+                let _ = handle.await;
+            }
+        }
+        for use_service in used_services {
+            if !self.used_services.contains_key(&use_service.forwarder_port) {
+                self.use_service(use_service);
+            }
+        }
+    }
+
+    async fn shutdown(&mut self) {
+        tracing::info!("shutting down");
+        self.shutdown.cancel();
+        self.sessions.close();
+        for rendezvous_point in self.rendezvous_points.clone() {
+            self.unregister_at(rendezvous_point);
+        }
+        // keep driving the swarm, sessions and unregistrations need it
+        let sessions = self.sessions.clone();
+        let drained = tokio::time::timeout(self.drain_timeout, async {
+            tokio::join!(sessions.wait(), tokio::time::sleep(UNREGISTER_DELAY))
+        });
+        tokio::pin!(drained);
+        loop {
+            tokio::select! {
+                result = &mut drained => {
+                    if result.is_err() {
+                        tracing::warn!(sessions = sessions.len(), "closing sessions not done in time");
+                    }
+                    break;
+                }
+                event = self.swarm.select_next_some() => {
+                    self.handle_event(event);
+                }
+            }
+        }
+    }
+
+    fn handle_event(&mut self, event: SwarmEvent<BehaviourEvent>) {
+        self.record_metrics(&event);
+        match event {
+            SwarmEvent::NewListenAddr { address, .. } => {
+                // NOTE: explicitly add every listening address in order to expose our LAN address
+                // to peers for direct LAN connectivity
+                self.swarm.add_external_address(address.clone());
+                tracing::info!(%address, "new listen address");
+            }
+
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                connection_id,
+                cause,
+                num_established,
+                ..
+            } => {
+                if let Some(peer) = self.peers.get_mut(&peer_id) {
+                    peer.connections.remove(&connection_id);
+                }
+                self.update_connected_peers(peer_id);
+                if let Some(error) = cause {
+                    tracing::info!(%peer_id, ?error, "conneciton closed with error");
+                }
+                self.relay_metrics.connection_closed(&peer_id, num_established);
+                if num_established == 0 {
+                    self.emit(Event::PeerDisconnected { peer_id });
+                    self.prune_peers();
+                    if let Some(listener_id) = self.relays.disconnected(&peer_id) {
+                        tracing::warn!(relay = %peer_id, "lost connection to relay");
+                        self.swarm.remove_listener(listener_id);
+                        self.reserve_relays();
+                    }
+                }
+            }
+
+            SwarmEvent::ConnectionEstablished {
+                peer_id,
+                connection_id,
+                endpoint,
+                num_established,
+                ..
+            } => {
+                self.seen(peer_id).connections.insert(
+                    connection_id,
+                    ConnectionInfo {
+                        direction: if endpoint.is_dialer() {
+                            Direction::Outbound
+                        } else {
+                            Direction::Inbound
+                        },
+                        remote_address: endpoint.get_remote_address().clone(),
+                        relayed: endpoint.is_relayed(),
+                    },
+                );
+                self.update_connected_peers(peer_id);
+                if self.close_relayed {
+                    self.close_relayed_connections(peer_id);
+                }
+                if num_established.get() == 1 {
+                    self.emit(Event::PeerConnected { peer_id });
+                }
+                // rendezvous points and relays are well connected, bootstrap the DHT from them
+                if let ConnectedPoint::Dialer { address, .. } = &endpoint {
+                    if self.pending_rendezvous_connections.contains(&connection_id)
+                        || self.relays.contains(&peer_id)
+                    {
+                        self.add_kad_address(peer_id, address.clone());
+                    }
+                }
+                if self.pending_rendezvous_connections.take(&connection_id).is_some() {
+                    // rendezvous points are publicly reachable, ask them to probe our addresses
+                    let address = endpoint.get_remote_address().clone();
+                    self.swarm
+                        .behaviour_mut()
+                        .autonat
+                        .add_server(peer_id, Some(address));
+                    self.rendezvous_points.insert(peer_id);
+                    tokio::spawn(talk_to_rendezvous(self.app_tx.clone(), peer_id));
+                    tracing::info!(rendezvous_point = %peer_id, "connected to rendezvous");
+                }
+
+                if self.pending_relay_connections.remove(&connection_id)
+                    && self.relays.is_dialing(&peer_id)
+                {
+                    tracing::info!(%peer_id, "connected to relay");
+                    if let ConnectedPoint::Dialer { address, .. } = endpoint {
+                        self.listen_via_relay(peer_id, address);
+                    }
+                }
+            }
+
+            SwarmEvent::OutgoingConnectionError {
+                connection_id,
+                peer_id,
+                error,
+            } => {
+                if self.pending_relay_connections.take(&connection_id).is_some() {
+                    tracing::error!(relay = ?peer_id, ?error, "connetion to relay server error");
+                    if let Some(relay) = peer_id {
+                        self.relays.failed(&relay);
+                        self.reserve_relays();
+                    }
+                }
+                if self.pending_rendezvous_connections.take(&connection_id).is_some() {
+                    tracing::error!(rendezvous_point = ?peer_id, ?error, "connection to rendezvous point error");
+                }
+                // e.g. opening a proxy stream to a provider we never discovered
+                if let (Some(peer_id), DialError::NoAddresses) = (peer_id, &error) {
+                    self.find_peer(peer_id);
+                }
+            }
+
+            // once `/identify` did its job, we know our external address and can register
+            SwarmEvent::Behaviour(BehaviourEvent::RendezvousClient(
+                rendezvous::client::Event::Registered {
+                    namespace,
+                    ttl,
+                    rendezvous_node: rendezvous_point,
+                },
+            )) => {
+                tracing::info!(%rendezvous_point, %namespace, %ttl, "successfully registered at rendezvout point");
+                self.emit(Event::Registered {
+                    rendezvous_point,
+                    namespace: namespace.to_string(),
+                });
+            }
+
+            SwarmEvent::Behaviour(BehaviourEvent::RendezvousClient(
+                rendezvous::client::Event::RegisterFailed {
+                    rendezvous_node: rendezvous_point,
+                    namespace,
+                    error,
+                },
+            )) => {
+                tracing::error!(%rendezvous_point, %namespace, ?error, "failed to register at rendezvout point");
+            }
+
+            SwarmEvent::Behaviour(BehaviourEvent::RendezvousClient(
+                rendezvous::client::Event::Discovered {
+                    registrations,
+                    cookie: new_cookie,
+                    rendezvous_node,
+                },
+            )) => {
+                let maybe_namespace = new_cookie.namespace();
+                if let Some(cookie) = self
+                    .rdv_cookies
+                    .get_mut(&(rendezvous_node, maybe_namespace.cloned()))
+                {
+                    *cookie = new_cookie.clone();
+                } else {
+                    self.rdv_cookies.insert(
+                        (rendezvous_node, maybe_namespace.cloned()),
+                        new_cookie.clone(),
+                    );
+                }
+                for registration in registrations.iter() {
+                    let peer_id = registration.record.peer_id();
+                    if peer_id != *self.swarm.local_peer_id() {
+                        let peer = self.seen(peer_id);
+                        peer.add_addresses(registration.record.addresses().iter().cloned());
+                        peer.discovered_at.insert(rendezvous_node);
+                        if registration.namespace != Namespace::from_static("relay") {
+                            peer.namespaces.insert(registration.namespace.to_string());
+                        }
+                        self.record_peer(peer_id);
+                    }
+                }
+                // Only dial relay immediately. Peers' addresses are maintained by rendezvous
+                // client behaviour itself, when dialing a peer by id, the swarm will get those
+                // addresses by extend_addresses_through_behaviour
+                if maybe_namespace.is_some_and(|ns| ns == "relay") {
+                    for registration in registrations {
+                        self.add_relay(&registration.record.peer_id());
+                    }
+                }
+            }
+
+            SwarmEvent::Behaviour(BehaviourEvent::RelayClient(
+                relay::client::Event::ReservationReqAccepted {
+                    relay_peer_id: relay,
+                    renewal,
+                    ..
+                },
+            )) => {
+                tracing::info!(%renewal, %relay, "relay accepted our reservation");
+                self.relays.accepted(relay);
+                self.emit(Event::ReservationAccepted { relay });
+                for rendezvous_point in self.rendezvous_points.clone() {
+                    self.register_at(&rendezvous_point);
+                }
+            }
+
+            SwarmEvent::Behaviour(BehaviourEvent::Ping(ping::Event {
+                peer,
+                result: Ok(rtt),
+                ..
+            })) => {
+                tracing::debug!(peer_id = %peer, ?rtt, "ping to peer success");
+                self.seen(peer).rtt = Some(rtt);
+            }
+
+            SwarmEvent::Behaviour(BehaviourEvent::Identify(identify::Event::Received {
+                peer_id,
+                info,
+                ..
+            })) => {
+                if info.protocols.contains(&KAD_PROTOCOL) {
+                    for address in info.listen_addrs.iter() {
+                        self.add_kad_address(peer_id, address.clone());
+                    }
+                }
+                let peer = self.seen(peer_id);
+                peer.add_addresses(info.listen_addrs);
+                peer.agent_version = Some(info.agent_version);
+                peer.protocols = info.protocols.iter().map(ToString::to_string).collect();
+                self.record_peer(peer_id);
+            }
+
+            SwarmEvent::Behaviour(BehaviourEvent::Relay(event)) => {
+                tracing::debug!(?event, "relay server event");
+            }
+
+            SwarmEvent::Behaviour(BehaviourEvent::Autonat(autonat::Event::StatusChanged {
+                old,
+                new,
+            })) => {
+                tracing::info!(?old, ?new, "nat status changed");
+                let reachability = match new {
+                    autonat::NatStatus::Public(_) => Reachability::Public,
+                    autonat::NatStatus::Private => Reachability::Private,
+                    autonat::NatStatus::Unknown => Reachability::Unknown,
+                };
+                self.set_reachability(reachability);
+            }
+
+            SwarmEvent::Behaviour(BehaviourEvent::Dcutr(dcutr::Event {
+                remote_peer_id,
+                result,
+            })) => {
+                self.direct_attempts.remove(&remote_peer_id);
+                let hole_punch = &mut self.peer_mut(remote_peer_id).hole_punch;
+                hole_punch.last_attempt = Some(unix_secs(SystemTime::now()));
+                match &result {
+                    Ok(connection_id) => {
+                        tracing::info!(peer_id = %remote_peer_id, %connection_id, "hole punch success");
+                        hole_punch.successes += 1;
+                    }
+                    Err(error) => {
+                        tracing::warn!(peer_id = %remote_peer_id, ?error, "hole punch failed");
+                        hole_punch.failures += 1;
+                        hole_punch.last_error = Some(error.to_string());
+                    }
+                }
+                self.hole_punches
+                    .get_or_create(&HolePunchLabels {
+                        result: if result.is_ok() { "success" } else { "failure" },
+                    })
+                    .inc();
+                self.emit(Event::HolePunch {
+                    peer_id: remote_peer_id,
+                    success: result.is_ok(),
+                });
+            }
+
+            SwarmEvent::Behaviour(BehaviourEvent::Mdns(mdns::Event::Discovered(discovered))) => {
+                let mut lan_addresses: HashMap<PeerId, Vec<Multiaddr>> = HashMap::new();
+                for (peer_id, address) in discovered {
+                    lan_addresses.entry(peer_id).or_default().push(address);
+                }
+                for (peer_id, addresses) in lan_addresses {
+                    tracing::info!(%peer_id, ?addresses, "discovered peer on local network");
+                    for address in addresses.iter() {
+                        self.swarm
+                            .behaviour_mut()
+                            .address_book
+                            .add_address(peer_id, address.clone());
+                    }
+                    let peer = self.seen(peer_id);
+                    peer.add_addresses(addresses.iter().cloned());
+                    peer.mdns = true;
+                    // Prefer a direct LAN connection, even if we are already connected through
+                    // a relay
+                    let directly_connected = peer.connections.values().any(|c| !c.relayed);
+                    self.record_peer(peer_id);
+                    if !directly_connected {
+                        let dial_opts = DialOpts::peer_id(peer_id)
+                            .addresses(addresses)
+                            .condition(PeerCondition::Always)
+                            .build();
+                        if let Err(error) = self.swarm.dial(dial_opts) {
+                            tracing::warn!(%peer_id, ?error, "dial local network peer error");
+                        }
+                    }
+                }
+            }
+
+            SwarmEvent::Behaviour(BehaviourEvent::Mdns(mdns::Event::Expired(expired))) => {
+                for (peer_id, address) in expired {
+                    tracing::debug!(%peer_id, %address, "local network peer expired");
+                    if let Some(peer) = self.peers.get_mut(&peer_id) {
+                        peer.mdns = false;
+                    }
+                }
+            }
+
+            SwarmEvent::Behaviour(BehaviourEvent::Kad(kad::Event::OutboundQueryProgressed {
+                result,
+                step,
+                ..
+            })) => {
+                self.handle_kad_query(result, step.last);
+            }
+
+            // relay reservations are denied or lost by closing their circuit listener
+            SwarmEvent::ListenerClosed {
+                listener_id,
+                reason,
+                ..
+            } => {
+                if let Some(relay) = self.relays.listener_closed(listener_id) {
+                    tracing::warn!(%relay, ?reason, "relay reservation closed");
+                    self.reserve_relays();
+                }
+            }
+
+            SwarmEvent::ExternalAddrConfirmed { .. } => {
+                // TODO work around the clone here
+                for rendezvous_point in self.rendezvous_points.clone() {
+                    self.register_at(&rendezvous_point);
+                }
+            }
+
+            // TODO learn from identity's external address candidate
+            other => {
+                tracing::debug!("Unhandled {:?}", other);
+            }
+        }
+    }
+
+    // The record of a peer, created if we don't know it yet
+    fn peer_mut(&mut self, peer_id: PeerId) -> &mut PeerRecord {
+        let now = SystemTime::now();
+        self.peers.entry(peer_id).or_insert_with(|| PeerRecord {
+            addresses: Default::default(),
+            connections: Default::default(),
+            rtt: None,
+            agent_version: None,
+            protocols: Default::default(),
+            first_seen: now,
+            last_seen: now,
+            discovered_at: Default::default(),
+            namespaces: Default::default(),
+            mdns: false,
+            hole_punch: Default::default(),
+        })
+    }
+
+    // The record of a peer we just connected to, heard from or discovered
+    fn seen(&mut self, peer_id: PeerId) -> &mut PeerRecord {
+        let peer = self.peer_mut(peer_id);
+        peer.last_seen = SystemTime::now();
+        peer
+    }
+
+    // Forget disconnected peers we haven't seen in a long time, their addresses are likely stale
+    fn prune_peers(&mut self) {
+        let now = SystemTime::now();
+        self.peers.retain(|peer_id, peer| {
+            let expired = peer.connections.is_empty()
+                && now
+                    .duration_since(peer.last_seen)
+                    .is_ok_and(|unseen| unseen > PEER_EXPIRY);
+            if expired {
+                tracing::debug!(%peer_id, "forget peer");
+            }
+            !expired
+        });
+    }
+
+    fn record_metrics(&mut self, event: &SwarmEvent<BehaviourEvent>) {
+        self.metrics.record(event);
+        match event {
+            SwarmEvent::Behaviour(BehaviourEvent::Identify(event)) => self.metrics.record(event),
+            SwarmEvent::Behaviour(BehaviourEvent::Dcutr(event)) => self.metrics.record(event),
+            SwarmEvent::Behaviour(BehaviourEvent::Ping(event)) => self.metrics.record(event),
+            SwarmEvent::Behaviour(BehaviourEvent::Kad(event)) => self.metrics.record(event),
+            SwarmEvent::Behaviour(BehaviourEvent::Relay(event)) => {
+                self.metrics.record(event);
+                self.relay_metrics.record(event);
+            }
+            _ => {}
+        }
+    }
+
+    fn handle_kad_query(&mut self, result: kad::QueryResult, last: bool) {
+        match result {
+            kad::QueryResult::Bootstrap(Ok(kad::BootstrapOk { num_remaining, .. })) => {
+                if num_remaining > 0 || !last {
+                    return;
+                }
+                tracing::info!("dht bootstrapped");
+                let Some(kad) = self.swarm.behaviour_mut().kad.as_mut() else {
+                    return;
+                };
+                let key = kad::RecordKey::new(&KAD_RELAY_KEY);
+                if self.is_relay {
+                    if let Err(error) = kad.start_providing(key) {
+                        tracing::error!(?error, "provide relay in dht error");
+                    }
+                } else if self.reachability == Reachability::Private {
+                    kad.get_providers(key);
+                }
+            }
+            kad::QueryResult::Bootstrap(Err(error)) => {
+                tracing::warn!(?error, "dht bootstrap error");
+            }
+            kad::QueryResult::GetProviders(Ok(kad::GetProvidersOk::FoundProviders {
+                providers,
+                ..
+            })) => {
+                for relay in providers {
+                    if relay == *self.swarm.local_peer_id() {
+                        continue;
+                    }
+                    if !self.relays.contains(&relay) {
+                        tracing::info!(%relay, "found relay in dht");
+                    }
+                    self.add_relay(&relay);
+                }
+            }
+            kad::QueryResult::GetClosestPeers(Ok(kad::GetClosestPeersOk { key, peers })) => {
+                let Ok(target) = PeerId::from_bytes(&key) else {
+                    return;
+                };
+                for peer in peers.into_iter().filter(|peer| peer.peer_id == target) {
+                    tracing::info!(peer_id = %target, addresses = ?peer.addrs, "found peer in dht");
+                    for address in peer.addrs.iter() {
+                        self.swarm
+                            .behaviour_mut()
+                            .address_book
+                            .add_address(target, address.clone());
+                    }
+                    self.seen(target).add_addresses(peer.addrs);
+                    self.record_peer(target);
+                    // the relay could not be dialed before its addresses were known
+                    if self.relays.contains(&target) {
+                        self.reserve_relays();
+                    }
+                }
+            }
+            kad::QueryResult::GetClosestPeers(Err(error)) => {
+                tracing::warn!(?error, "dht peer lookup error");
+            }
+            other => {
+                tracing::debug!(?other, "dht query progressed");
+            }
+        }
+    }
+
+    fn add_kad_address(&mut self, peer_id: PeerId, address: Multiaddr) {
+        if let Some(kad) = self.swarm.behaviour_mut().kad.as_mut() {
+            kad.add_address(&peer_id, address);
+        }
+    }
+
+    // Look up the addresses of `peer_id` in the DHT, if enabled
+    fn find_peer(&mut self, peer_id: PeerId) {
+        if let Some(kad) = self.swarm.behaviour_mut().kad.as_mut() {
+            tracing::info!(%peer_id, "looking up peer in dht");
+            kad.get_closest_peers(peer_id);
+        }
+    }
+
+    fn use_service(&mut self, use_service: UseService) {
+        let peer_id = use_service.peer_id;
+        if self
+            .peers
+            .get(&peer_id)
+            .is_none_or(|peer| peer.addresses.is_empty())
+        {
+            self.find_peer(peer_id);
+        }
+        let forwarder_port = use_service.forwarder_port;
+        if self
+            .used_services
+            .get(&forwarder_port)
+            .is_some_and(|(_, handle)| !handle.is_finished())
+        {
+            tracing::error!(?use_service, "forwarder port already used by another service");
+            return;
+        }
+        let handle = tokio::spawn(proxy::use_service(
+            use_service.clone(),
+            self.control(),
+            self.active_sessions.clone(),
+            proxy::DirectWait {
+                timeout: self.direct_wait,
+                direct_peers: self.direct_peers.subscribe(),
+                connect_direct: self.connect_direct_tx.clone(),
+            },
+            self.sessions.clone(),
+            self.shutdown.clone(),
+        ));
+        self.used_services
+            .insert(forwarder_port, (use_service, handle));
+    }
+
+    // Proxied sessions are kept
+    fn stop_using_service(&mut self, forwarder_port: u16) {
+        if let Some((use_service, handle)) = self.used_services.remove(&forwarder_port) {
+            tracing::info!(?use_service, "stop using service");
+            handle.abort();
+        }
+    }
+
+    fn record_peer(&self, peer_id: PeerId) {
+        let (Some(known_peers), Some(peer)) = (&self.known_peers, self.peers.get(&peer_id)) else {
+            return;
+        };
+        let _ = known_peers.send(KnownPeer {
+            peer_id,
+            addresses: peer.addresses.iter().cloned().collect(),
+            first_seen: unix_secs(peer.first_seen),
+            last_seen: unix_secs(peer.last_seen),
+        });
+    }
+
+    fn emit(&self, event: Event) {
+        // no subscribers is fine
+        let _ = self.events.send(event);
+    }
+
+    // Reject addresses of disabled transports rather than failing to dial them
+    fn check_transport(&self, address: &Multiaddr) -> bool {
+        if let Err(error) = self.transport.check(address) {
+            tracing::error!(%error, "unsupported address");
+            return false;
+        }
+        true
+    }
+
+    fn add_rendezvous(&mut self, rendezvous_point: &Multiaddr) {
+        if !self.check_transport(rendezvous_point) {
+            return;
+        }
+        let dial_opts = DialOpts::unknown_peer_id()
+            .address(rendezvous_point.clone())
+            .build();
+        let connection_id = dial_opts.connection_id();
+        if let Err(error) = self.swarm.dial(dial_opts) {
+            tracing::error!(?error, %rendezvous_point, "dial rendezvous point error");
+        } else {
+            self.pending_rendezvous_connections.insert(connection_id);
+            tracing::info!(%rendezvous_point, "dialing rendezvous point");
+        }
+    }
+
+    fn handle_command(&mut self, command: Command) {
+        match command {
+            Command::AddRendezvous(rendezvous_point) => {
+                self.add_rendezvous(&rendezvous_point);
+                self.rendezvous_list.push(rendezvous_point);
+            }
+            Command::TalkToRendezvous(rendezvous_point) => {
+                self.register_at(&rendezvous_point);
+
+                if self.needs_relay() {
+                    self.discover_relays(rendezvous_point);
+                }
+
+                for namespace in self.active_namespaces() {
+                    self.discover_at(rendezvous_point, namespace);
+                }
+            }
+            Command::GetNetworkInfo(resp) => {
+                let _ = resp.send(NetworkInfo {
+                    peer_id: *self.swarm.local_peer_id(),
+                    reachability: self.reachability,
+                    listen_addrs: self.swarm.listeners().cloned().collect(),
+                    peers: self
+                        .peers
+                        .iter()
+                        .map(|(peer_id, peer)| PeerInfo {
+                            peer_id: *peer_id,
+                            alias: None,
+                            connected: self.swarm.is_connected(peer_id),
+                            addresses: peer.addresses.iter().cloned().collect(),
+                            connections: peer.connections.values().cloned().collect(),
+                            rtt_ms: peer.rtt.map(|rtt| rtt.as_secs_f64() * 1000.0),
+                            agent_version: peer.agent_version.clone(),
+                            protocols: peer.protocols.clone(),
+                            first_seen: unix_secs(peer.first_seen),
+                            last_seen: unix_secs(peer.last_seen),
+                            discovered_at: peer.discovered_at.iter().cloned().collect(),
+                            namespaces: peer.namespaces.iter().cloned().collect(),
+                            mdns: peer.mdns,
+                            hole_punch: peer.hole_punch.clone(),
+                        })
+                        .collect(),
+                    namespaces: self.peers.iter().fold(
+                        BTreeMap::new(),
+                        |mut namespaces, (peer_id, peer)| {
+                            for namespace in peer.namespaces.iter() {
+                                namespaces
+                                    .entry(namespace.clone())
+                                    .or_insert_with(Vec::new)
+                                    .push(*peer_id);
+                            }
+                            namespaces
+                        },
+                    ),
+                    relays: self.relays.status(|relay| self.rtt(relay)),
+                });
+            }
+            Command::UseService(use_service) => {
+                // Immediately learn new peer addresses
+                self.discover_preers();
+                self.use_service(use_service);
+            }
+            Command::StopUsingService(forwarder_port) => {
+                self.stop_using_service(forwarder_port);
+            }
+            Command::GetSessions(resp) => {
+                let _ = resp.send(self.active_sessions.list());
+            }
+            Command::GetMetrics(resp) => {
+                let mut metrics = String::new();
+                if let Err(error) =
+                    prometheus_client::encoding::text::encode(&mut metrics, &self.registry)
+                {
+                    tracing::error!(?error, "encode metrics error");
+                }
+                let _ = resp.send(metrics);
+            }
+            Command::ProvideService(provide_service) => {
+                self.provide_service(provide_service);
+            }
+            Command::StopProvidingService(provide_service) => {
+                self.stop_providing_service(provide_service);
+            }
+            Command::AddPeerAddress(peer_address) => {
+                self.add_peer_address(peer_address);
+            }
+            Command::RemovePeerAddress(peer_address) => {
+                self.remove_peer_address(peer_address);
+            }
+            Command::AddNamespace(namespace) => {
+                self.add_namespace(namespace);
+            }
+            Command::RemoveNamespace(namespace) => {
+                self.remove_namespace(namespace);
+            }
+            Command::ConnectDirect(peer_id, resp) => {
+                let _ = resp.send(self.connect_direct(peer_id));
+            }
+            // handled by `run`, which awaits them
+            Command::Shutdown | Command::Reload(..) => {}
+        }
+    }
+
+    fn register_at(&mut self, rendezvous_point: &PeerId) {
+        let external_addresses = self.swarm.external_addresses().collect::<Vec<&Multiaddr>>();
+        tracing::info!(?external_addresses, %rendezvous_point, "registering addresses to rendezvous point");
+        for namespace in self.active_namespaces() {
+            self.register_in(rendezvous_point, namespace);
+        }
+
+        // Register as relay
+        if self.is_relay {
+            if let Err(error) = self.swarm.behaviour_mut().rendezvous_client.register(
+                rendezvous::Namespace::from_static("relay"),
+                *rendezvous_point,
+                Some(DEFAULT_RDV_REGISTRATION_TTL.as_secs()),
+            ) {
+                tracing::error!(%rendezvous_point, ?error, "failed to register as relay");
+            } else {
+                tracing::info!(%rendezvous_point, "registering as relay");
+            }
+        }
+    }
+
+    fn unregister_at(&mut self, rendezvous_point: PeerId) {
+        let mut namespaces = self.active_namespaces();
+        if self.is_relay {
+            namespaces.push(Namespace::from_static("relay"));
+        }
+        for namespace in namespaces {
+            tracing::info!(%rendezvous_point, %namespace, "unregistering");
+            self.swarm
+                .behaviour_mut()
+                .rendezvous_client
+                .unregister(namespace, rendezvous_point);
+        }
+    }
+
+    fn register_in(&mut self, rendezvous_point: &PeerId, namespace: Namespace) {
+        if let Err(error) = self.swarm.behaviour_mut().rendezvous_client.register(
+            namespace.clone(),
+            *rendezvous_point,
+            Some(DEFAULT_RDV_REGISTRATION_TTL.as_secs()),
+        ) {
+            tracing::error!(%rendezvous_point, %namespace, ?error, "failed to register");
+        } else {
+            tracing::info!(%rendezvous_point, %namespace, "registering");
+        }
+    }
+
+    fn discover_at(&mut self, rendezvous_point: PeerId, namespace: Namespace) {
+        let cookie = self
+            .rdv_cookies
+            .get(&(rendezvous_point, Some(namespace.clone())))
+            .cloned();
+        self.swarm.behaviour_mut().rendezvous_client.discover(
+            Some(namespace),
+            cookie,
+            None,
+            rendezvous_point,
+        );
+    }
+
+    // Namespaces we register and discover peers under
+    fn active_namespaces(&self) -> Vec<Namespace> {
+        if self.namespaces.is_empty() {
+            vec![Namespace::from_static(DEFAULT_NAMESPACE)]
+        } else {
+            self.namespaces.clone()
+        }
+    }
+
+    fn set_namespaces(&mut self, namespaces: Vec<Namespace>) {
+        let old = self.active_namespaces();
+        self.namespaces = namespaces;
+        let new = self.active_namespaces();
+        for rendezvous_point in self.rendezvous_points.clone() {
+            for namespace in old.iter().filter(|ns| !new.contains(ns)) {
+                tracing::info!(%rendezvous_point, %namespace, "unregistering");
+                self.swarm
+                    .behaviour_mut()
+                    .rendezvous_client
+                    .unregister(namespace.clone(), rendezvous_point);
+            }
+            for namespace in new.iter().filter(|ns| !old.contains(ns)) {
+                self.register_in(&rendezvous_point, namespace.clone());
+                self.discover_at(rendezvous_point, namespace.clone());
+            }
+        }
+    }
+
+    fn discover_relays(&mut self, rendezvous_point: PeerId) {
+        self.swarm.behaviour_mut().rendezvous_client.discover(
+            Some(rendezvous::Namespace::from_static("relay")),
+            self.rdv_cookies
+                .get(&(rendezvous_point, Some(Namespace::from_static("relay"))))
+                .cloned(),
+            None,
+            rendezvous_point,
+        );
+    }
+
+    fn needs_relay(&self) -> bool {
+        !self.is_relay && self.reachability == Reachability::Private
+    }
+
+    fn set_reachability(&mut self, reachability: Reachability) {
+        if reachability == self.reachability {
+            return;
+        }
+        self.reachability = reachability;
+        self.emit(Event::ReachabilityChanged { reachability });
+        match reachability {
+            Reachability::Private if !self.is_relay => {
+                for rendezvous_point in self.rendezvous_points.clone() {
+                    self.discover_relays(rendezvous_point);
+                }
+                if let Some(kad) = self.swarm.behaviour_mut().kad.as_mut() {
+                    kad.get_providers(kad::RecordKey::new(&KAD_RELAY_KEY));
+                }
+                self.reserve_relays();
+            }
+            Reachability::Public => {
+                // directly reachable, give up our reservations
+                for listener_id in self.relays.release() {
+                    self.swarm.remove_listener(listener_id);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn discover_preers(&mut self) {
+        for rendezvous_point in self.rendezvous_points.clone() {
+            for namespace in self.active_namespaces() {
+                self.discover_at(rendezvous_point, namespace);
+            }
+        }
+    }
+
+    fn rtt(&self, peer_id: &PeerId) -> Option<Duration> {
+        self.peers.get(peer_id).and_then(|peer| peer.rtt)
+    }
+
+    fn listen_via_relay(&mut self, relay: PeerId, address: Multiaddr) {
+        let p2p_suffix = Protocol::P2p(relay);
+        let address_with_p2p = if !address.ends_with(&Multiaddr::empty().with(p2p_suffix.clone())) {
+            address.clone().with(p2p_suffix)
+        } else {
+            address.clone()
+        };
+
+        match self
+            .swarm
+            .listen_on(address_with_p2p.with(Protocol::P2pCircuit))
+        {
+            Ok(listener_id) => {
+                tracing::info!(%relay, %address, "listen on circuit relay address success");
+                self.relays.listening(relay, listener_id);
+            }
+            Err(error) => {
+                tracing::error!(%relay, %address, ?error, "listen on circuit relay address error");
+                self.relays.failed(&relay);
+            }
+        }
+    }
+
+    fn add_relay(&mut self, relay: &PeerId) {
+        self.relays.add(*relay);
+        self.reserve_relays();
+    }
+
+    fn is_direct(&self, peer_id: &PeerId) -> bool {
+        self.peers
+            .get(peer_id)
+            .is_some_and(|peer| peer.connections.values().any(|c| !c.relayed))
+    }
+
+    fn update_connected_peers(&mut self, peer_id: PeerId) {
+        let (direct, relayed) = self.peers.get(&peer_id).map_or((false, false), |peer| {
+            (
+                peer.connections.values().any(|c| !c.relayed),
+                peer.connections.values().any(|c| c.relayed),
+            )
+        });
+        for (peers, connected) in [(&self.direct_peers, direct), (&self.relayed_peers, relayed)] {
+            peers.send_if_modified(|peers| {
+                if connected {
+                    peers.insert(peer_id)
+                } else {
+                    peers.remove(&peer_id)
+                }
+            });
+        }
+    }
+
+    // Relayed connections are redundant once we have a direct one, streams still open on them
+    // are reset
+    fn close_relayed_connections(&mut self, peer_id: PeerId) {
+        let Some(peer) = self.peers.get(&peer_id) else {
+            return;
+        };
+        if !peer.connections.values().any(|c| !c.relayed) {
+            return;
+        }
+        let relayed = peer
+            .connections
+            .iter()
+            .filter(|(_, c)| c.relayed)
+            .map(|(connection_id, _)| *connection_id)
+            .collect::<Vec<_>>();
+        for connection_id in relayed {
+            tracing::info!(%peer_id, %connection_id, "closing relayed connection, connected directly");
+            self.swarm.close_connection(connection_id);
+        }
+    }
+
+    // Dial the peer again, directly if we can and otherwise through a relay: the remote starts
+    // a hole punch on every new relayed connection. Only one attempt per peer is made at a time,
+    // until its hole punch outcome or DIRECT_ATTEMPT_TIMEOUT.
+    fn connect_direct(&mut self, peer_id: PeerId) -> bool {
+        if self.is_direct(&peer_id) {
+            return true;
+        }
+        let now = Instant::now();
+        self.direct_attempts
+            .retain(|_, at| now.duration_since(*at) < DIRECT_ATTEMPT_TIMEOUT);
+        if self.direct_attempts.contains_key(&peer_id) {
+            tracing::debug!(%peer_id, "direct connection attempt already in progress");
+            return true;
+        }
+        let dial_opts = DialOpts::peer_id(peer_id)
+            .condition(PeerCondition::Always)
+            .build();
+        if let Err(error) = self.swarm.dial(dial_opts) {
+            tracing::warn!(%peer_id, ?error, "dial for direct connection error");
+            return false;
+        }
+        self.direct_attempts.insert(peer_id, now);
+        tracing::info!(%peer_id, "attempting direct connection");
+        true
+    }
+
+    // Reserve circuits on the best relays until `max_relays` reservations are held or pending
+    fn reserve_relays(&mut self) {
+        if !self.needs_relay() {
+            return;
+        }
+        for relay in self.relays.select(|relay| self.rtt(relay)) {
+            // already connected, e.g. a DHT bootstrap peer later found to be a relay
+            let dialed_address = self.peers.get(&relay).and_then(|peer| {
+                peer.connections
+                    .values()
+                    .find(|c| c.direction == Direction::Outbound && !c.relayed)
+                    .map(|c| c.remote_address.clone())
+            });
+            if let Some(address) = dialed_address {
+                self.listen_via_relay(relay, address);
+                continue;
+            }
+            let dial_opts = DialOpts::peer_id(relay)
+                .condition(PeerCondition::NotDialing)
+                .build();
+            let connection_id = dial_opts.connection_id();
+            self.relays.dialing(relay);
+            if let Err(error) = self.swarm.dial(dial_opts) {
+                tracing::error!(?error, %relay, "dial relay server error");
+                self.relays.failed(&relay);
+            } else {
+                self.pending_relay_connections.insert(connection_id);
+                tracing::info!(%relay, "dialing relay server");
+            }
+        }
+    }
+}
+
+// Sleep until `at`, forever if `None`
+async fn sleep_until(at: Option<Instant>) {
+    match at {
+        Some(at) => tokio::time::sleep_until(at.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+// TODO: need to be able to cancel this (when a rendezvous address is deleted)
+async fn talk_to_rendezvous(app_tx: mpsc::Sender<Command>, peer_id: PeerId) {
+    loop {
+        if app_tx
+            .send(Command::TalkToRendezvous(peer_id))
+            .await
+            .is_err()
+        {
+            break;
+        }
+        tokio::time::sleep(DEFAULT_RDV_REFRESH).await;
+    }
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
@@ -0,0 +1,8 @@
+//! Protobuf messages exchanged between preers nodes, generated from `proto/*.proto` at
+//! build time.
+
+include!(concat!(env!("OUT_DIR"), "/generated/mod.rs"));
+
+pub use self::proxy::pb::{
+    mod_UseServiceRespV2::Status, UseServiceReq, UseServiceResp, UseServiceRespV2,
+};
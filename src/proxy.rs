@@ -0,0 +1,901 @@
+use crate::data::{Direction, Event, SessionInfo, UseService};
+use crate::proto;
+use asynchronous_codec::Framed;
+use futures::{stream::SelectAll, AsyncReadExt, SinkExt, StreamExt};
+use libp2p::{PeerId, Stream, StreamProtocol};
+use libp2p_stream as stream;
+use pin_project::pin_project;
+use quick_protobuf::MessageRead;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::io;
+use std::net::Ipv4Addr;
+use std::result::Result;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::copy_bidirectional;
+use tokio::io::ReadBuf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio_util::compat::{FuturesAsyncReadCompatExt, FuturesAsyncWriteCompatExt};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+/// Wire format spoken on a proxy stream.
+///
+/// - `V1`: the provider answers `allowed` before connecting to the service.
+/// - `V2`: the provider connects to the service first and answers with a status, so the
+///   consumer learns about unreachable services instead of getting an empty stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ProxyVersion {
+    V1,
+    V2,
+}
+
+// Supported protocols, newest first. The unversioned `/preers-proxy` is kept for nodes
+// predating protocol versioning and speaks the V1 wire format.
+const PROXY_PROTOCOLS: [(StreamProtocol, ProxyVersion); 3] = [
+    (StreamProtocol::new("/preers/proxy/2.0.0"), ProxyVersion::V2),
+    (StreamProtocol::new("/preers/proxy/1.0.0"), ProxyVersion::V1),
+    (StreamProtocol::new("/preers-proxy"), ProxyVersion::V1),
+];
+const MAX_MESSAGE_SIZE: usize = 1024;
+
+/// Errors opening a proxied stream with [`connect`].
+#[derive(Debug)]
+pub enum ConnectError {
+    /// The remote supports none of our proxy protocol versions.
+    UnsupportedProtocol,
+    /// Opening the stream or exchanging the handshake failed.
+    Io(io::Error),
+    /// The remote does not provide the requested service.
+    NotAllowed,
+    /// The remote provides the service but could not connect to it.
+    ConnectFailed,
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnsupportedProtocol => write!(f, "remote does not support the proxy protocol"),
+            Self::Io(error) => write!(f, "proxy handshake error: {error}"),
+            Self::NotAllowed => write!(f, "service not provided by remote"),
+            Self::ConnectFailed => write!(f, "remote failed to connect to service"),
+        }
+    }
+}
+
+impl std::error::Error for ConnectError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ConnectError {
+    fn from(error: io::Error) -> Self {
+        Self::Io(error)
+    }
+}
+
+/// Waiting for a direct connection with a peer before proxying over a relay.
+#[derive(Clone)]
+pub struct DirectWait {
+    /// Don't wait if zero
+    pub timeout: Duration,
+    /// Peers we have a direct connection with
+    pub direct_peers: watch::Receiver<HashSet<PeerId>>,
+    /// Request a direct connection attempt with a peer
+    pub connect_direct: mpsc::Sender<PeerId>,
+}
+
+impl DirectWait {
+    async fn wait(&mut self, peer_id: PeerId) {
+        if self.timeout.is_zero() || self.direct_peers.borrow().contains(&peer_id) {
+            return;
+        }
+        let _ = self.connect_direct.send(peer_id).await;
+        let direct = self
+            .direct_peers
+            .wait_for(|direct_peers| direct_peers.contains(&peer_id));
+        if tokio::time::timeout(self.timeout, direct).await.is_err() {
+            tracing::info!(%peer_id, "no direct connection in time, proxying over relay");
+        }
+    }
+
+    // Streams are opened on a direct connection if there is any
+    fn is_relayed(&self, peer_id: &PeerId) -> bool {
+        !self.direct_peers.borrow().contains(peer_id)
+    }
+}
+
+/// Forward connections accepted on the local `forwarder_port` to the peer providing the service.
+///
+/// Sessions are spawned on `sessions`, no more connections are accepted once `shutdown` is
+/// cancelled.
+pub async fn use_service(
+    use_service: UseService,
+    control: stream::Control,
+    active: ActiveSessions,
+    direct_wait: DirectWait,
+    sessions: TaskTracker,
+    shutdown: CancellationToken,
+) {
+    let Ok(listener) = TcpListener::bind((Ipv4Addr::LOCALHOST, use_service.forwarder_port)).await else {
+        tracing::error!(?use_service, "listen local error");
+        return;
+    };
+    tracing::info!(?use_service, "listening on local host");
+    loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            _ = shutdown.cancelled() => {
+                tracing::info!(?use_service, "stop listening on local host");
+                return;
+            }
+        };
+        match accepted {
+            Ok((local_stream, from_addr)) => {
+                tracing::info!(?use_service, %from_addr, "accepted incoming request");
+                sessions.spawn(handle_outbound(
+                    local_stream,
+                    control.clone(),
+                    use_service.clone(),
+                    active.clone(),
+                    direct_wait.clone(),
+                ));
+            }
+            Err(error) => {
+                tracing::error!(?error, "accept stream error");
+                // TODO just return or handle error and continue?
+                // return;
+                continue;
+            }
+        }
+    }
+}
+
+async fn handle_outbound(
+    local_stream: TcpStream,
+    mut control: stream::Control,
+    use_service: UseService,
+    active: ActiveSessions,
+    mut direct_wait: DirectWait,
+) {
+    direct_wait.wait(use_service.peer_id).await;
+    let session = Session {
+        active,
+        peer_id: use_service.peer_id,
+        direction: Direction::Outbound,
+        relayed: Some(direct_wait.is_relayed(&use_service.peer_id)),
+        host: use_service.host,
+        port: use_service.port,
+    };
+    match connect(&mut control, session.peer_id, session.host.clone(), session.port).await {
+        Ok(remote_stream) => session.proxy(local_stream, remote_stream).await,
+        Err(error) => {
+            tracing::error!(peer_id = %session.peer_id, %error, "open proxied stream error");
+            session.failed(error);
+        }
+    }
+}
+
+/// Open a stream to `host:port` as provided by `peer_id`, negotiating the newest proxy
+/// protocol version the remote supports.
+///
+/// Once this resolves the stream is connected to the remote service: bytes written to it are
+/// forwarded to the service and its replies can be read back. Use `tokio_util::compat` to
+/// drive it with tokio IO.
+pub async fn connect(
+    control: &mut stream::Control,
+    peer_id: PeerId,
+    host: String,
+    port: u16,
+) -> Result<Stream, ConnectError> {
+    connect_with(control, peer_id, host, port, &PROXY_PROTOCOLS).await
+}
+
+// `connect` with only the given protocols, newest first
+async fn connect_with(
+    control: &mut stream::Control,
+    peer_id: PeerId,
+    host: String,
+    port: u16,
+    protocols: &[(StreamProtocol, ProxyVersion)],
+) -> Result<Stream, ConnectError> {
+    let (remote_stream, version) = open_stream(control, peer_id, protocols).await?;
+    let msg = proto::UseServiceReq {
+        host,
+        port: port as u32,
+    };
+    match version {
+        ProxyVersion::V1 => {
+            let (proto::UseServiceResp { allowed }, remote_stream) =
+                request(remote_stream, msg).await?;
+            if !allowed {
+                return Err(ConnectError::NotAllowed);
+            }
+            Ok(remote_stream)
+        }
+        ProxyVersion::V2 => {
+            let (proto::UseServiceRespV2 { status }, remote_stream) =
+                request(remote_stream, msg).await?;
+            match status {
+                proto::Status::OK => Ok(remote_stream),
+                proto::Status::CONNECT_FAILED => Err(ConnectError::ConnectFailed),
+                _ => Err(ConnectError::NotAllowed),
+            }
+        }
+    }
+}
+
+// Open a proxy stream with the first of `protocols` the remote supports
+async fn open_stream(
+    control: &mut stream::Control,
+    peer_id: PeerId,
+    protocols: &[(StreamProtocol, ProxyVersion)],
+) -> Result<(Stream, ProxyVersion), ConnectError> {
+    for (protocol, version) in protocols {
+        match control.open_stream(peer_id, protocol.clone()).await {
+            Ok(stream) => {
+                tracing::debug!(%peer_id, %protocol, "negotiated proxy protocol");
+                return Ok((stream, *version));
+            }
+            Err(stream::OpenStreamError::UnsupportedProtocol(_)) => {
+                tracing::debug!(%peer_id, %protocol, "proxy protocol not supported by remote");
+            }
+            Err(stream::OpenStreamError::Io(error)) => return Err(error.into()),
+            Err(error) => return Err(io::Error::other(error).into()),
+        }
+    }
+    Err(ConnectError::UnsupportedProtocol)
+}
+
+// Send the use service request and wait for the version specific response
+async fn request<Resp>(
+    remote_stream: Stream,
+    msg: proto::UseServiceReq,
+) -> Result<(Resp, Stream), ConnectError>
+where
+    Resp: for<'a> MessageRead<'a>,
+{
+    let mut framed_stream = Framed::new(
+        remote_stream,
+        quick_protobuf_codec::Codec::<proto::UseServiceReq, Resp>::new(MAX_MESSAGE_SIZE),
+    );
+    tracing::debug!(?msg, "sending request");
+    framed_stream.send(msg).await.map_err(io::Error::from)?;
+    tracing::debug!("sent request");
+    let resp = framed_stream
+        .next()
+        .await
+        .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?
+        .map_err(io::Error::from)?;
+    Ok((resp, framed_stream.into_inner()))
+}
+
+/// Services peers may use through us, as `(host, port)`.
+pub type ProvidedServices = Arc<Mutex<HashSet<(String, u16)>>>;
+
+/// Serve incoming proxy streams for `provided_services`, like [`use_service`] until `shutdown`
+/// is cancelled.
+///
+/// `direct_peers` and `relayed_peers` are the peers we have a direct and a relayed connection
+/// with, telling which connection a session is proxied over.
+pub async fn provide_services(
+    provided_services: ProvidedServices,
+    mut control: stream::Control,
+    active: ActiveSessions,
+    direct_peers: watch::Receiver<HashSet<PeerId>>,
+    relayed_peers: watch::Receiver<HashSet<PeerId>>,
+    sessions: TaskTracker,
+    shutdown: CancellationToken,
+) {
+    let mut incoming = accept_streams(&mut control, &PROXY_PROTOCOLS);
+    loop {
+        tokio::select! {
+            Some((peer_id, stream, version)) = incoming.next() => {
+                tracing::info!(%peer_id, ?version, "incoming use service request from peer");
+                sessions.spawn(handle_inbound(
+                    provided_services.clone(),
+                    peer_id,
+                    stream,
+                    version,
+                    active.clone(),
+                    inbound_relayed(&direct_peers, &relayed_peers, &peer_id),
+                ));
+            }
+            // dropping the incoming streams refuses further requests
+            _ = shutdown.cancelled() => {
+                break;
+            }
+            else => {
+                break;
+            }
+        }
+    }
+}
+
+// Incoming streams of all `protocols`, with the version each speaks
+fn accept_streams(
+    control: &mut stream::Control,
+    protocols: &[(StreamProtocol, ProxyVersion)],
+) -> SelectAll<impl futures::Stream<Item = (PeerId, Stream, ProxyVersion)>> {
+    let mut incoming = SelectAll::new();
+    for (protocol, version) in protocols {
+        let version = *version;
+        let streams = control
+            .accept(protocol.clone())
+            .expect("should get incoming streams");
+        incoming.push(streams.map(move |(peer_id, stream)| (peer_id, stream, version)));
+    }
+    incoming
+}
+
+// The remote picks the connection of inbound streams, which we can only tell when all our
+// connections with the peer are of one kind
+fn inbound_relayed(
+    direct_peers: &watch::Receiver<HashSet<PeerId>>,
+    relayed_peers: &watch::Receiver<HashSet<PeerId>>,
+    peer_id: &PeerId,
+) -> Option<bool> {
+    let direct = direct_peers.borrow().contains(peer_id);
+    let relayed = relayed_peers.borrow().contains(peer_id);
+    (direct != relayed).then_some(relayed)
+}
+
+async fn handle_inbound(
+    provided_services: ProvidedServices,
+    peer_id: PeerId,
+    remote_stream: Stream,
+    version: ProxyVersion,
+    active: ActiveSessions,
+    relayed: Option<bool>,
+) {
+    let session = |host: String, port: u32| Session {
+        active,
+        peer_id,
+        direction: Direction::Inbound,
+        relayed,
+        host,
+        port: port as u16,
+    };
+    let accepted = match version {
+        ProxyVersion::V1 => handle_inbound_v1(provided_services, remote_stream, session).await,
+        ProxyVersion::V2 => handle_inbound_v2(provided_services, remote_stream, session).await,
+    };
+    if let Some((session, local_stream, remote_stream)) = accepted {
+        session.proxy(local_stream, remote_stream).await;
+    }
+}
+
+// V1: answer whether the service is provided, then connect to it
+async fn handle_inbound_v1(
+    provided_services: ProvidedServices,
+    remote_stream: Stream,
+    session: impl FnOnce(String, u32) -> Session,
+) -> Option<(Session, TcpStream, Stream)> {
+    let mut framed_stream = Framed::new(
+        remote_stream,
+        quick_protobuf_codec::Codec::<proto::UseServiceResp, proto::UseServiceReq>::new(
+            MAX_MESSAGE_SIZE,
+        ),
+    );
+    let Some(Ok(proto::UseServiceReq { host, port })) = framed_stream.next().await else {
+        tracing::error!("receive use service request error");
+        return None;
+    };
+    tracing::debug!(%host, %port, "received use service request from peer");
+    let session = session(host.clone(), port);
+    if !is_provided(&provided_services, &host, port) {
+        tracing::warn!(%host, %port, "incoming service request not allowed");
+        session.failed("service not provided");
+        let _ = framed_stream
+            .send(proto::UseServiceResp { allowed: false })
+            .await;
+        let _ = framed_stream.close().await;
+        return None;
+    }
+    if let Err(error) = framed_stream
+        .send(proto::UseServiceResp { allowed: true })
+        .await
+    {
+        tracing::warn!(?error, "send use service response to remote error");
+        return None;
+    }
+    let remote_stream = framed_stream.into_inner();
+    let local_stream = match TcpStream::connect((host.as_str(), port as u16)).await {
+        Ok(local_stream) => local_stream,
+        Err(error) => {
+            tracing::error!(%host, %port, ?error, "connect to provided service error");
+            session.failed(format!("connect to service error: {error}"));
+            return None;
+        }
+    };
+    Some((session, local_stream, remote_stream))
+}
+
+// V2: connect to the service first, then answer with the outcome
+async fn handle_inbound_v2(
+    provided_services: ProvidedServices,
+    remote_stream: Stream,
+    session: impl FnOnce(String, u32) -> Session,
+) -> Option<(Session, TcpStream, Stream)> {
+    let mut framed_stream = Framed::new(
+        remote_stream,
+        quick_protobuf_codec::Codec::<proto::UseServiceRespV2, proto::UseServiceReq>::new(
+            MAX_MESSAGE_SIZE,
+        ),
+    );
+    let Some(Ok(proto::UseServiceReq { host, port })) = framed_stream.next().await else {
+        tracing::error!("receive use service request error");
+        return None;
+    };
+    tracing::debug!(%host, %port, "received use service request from peer");
+    let session = session(host.clone(), port);
+    let (status, maybe_local_stream) = if !is_provided(&provided_services, &host, port) {
+        tracing::warn!(%host, %port, "incoming service request not allowed");
+        session.failed("service not provided");
+        (proto::Status::NOT_ALLOWED, None)
+    } else {
+        match TcpStream::connect((host.as_str(), port as u16)).await {
+            Ok(local_stream) => (proto::Status::OK, Some(local_stream)),
+            Err(error) => {
+                tracing::error!(%host, %port, ?error, "connect to provided service error");
+                session.failed(format!("connect to service error: {error}"));
+                (proto::Status::CONNECT_FAILED, None)
+            }
+        }
+    };
+    if let Err(error) = framed_stream
+        .send(proto::UseServiceRespV2 { status })
+        .await
+    {
+        tracing::warn!(?error, "send use service response to remote error");
+        return None;
+    }
+    let Some(local_stream) = maybe_local_stream else {
+        let _ = framed_stream.close().await;
+        return None;
+    };
+    Some((session, local_stream, framed_stream.into_inner()))
+}
+
+fn is_provided(provided_services: &Mutex<HashSet<(String, u16)>>, host: &str, port: u32) -> bool {
+    provided_services
+        .lock()
+        .unwrap()
+        .contains(&(host.to_string(), port as u16))
+}
+
+/// Sessions being proxied, reported on the event bus as they open and close.
+#[derive(Clone)]
+pub struct ActiveSessions {
+    events: broadcast::Sender<Event>,
+    next_id: Arc<AtomicU64>,
+    sessions: Arc<Mutex<BTreeMap<u64, SessionInfo>>>,
+}
+
+impl ActiveSessions {
+    pub fn new(events: broadcast::Sender<Event>) -> Self {
+        Self {
+            events,
+            next_id: Default::default(),
+            sessions: Default::default(),
+        }
+    }
+
+    /// Sessions open right now, oldest first.
+    pub fn list(&self) -> Vec<SessionInfo> {
+        self.sessions.lock().unwrap().values().cloned().collect()
+    }
+
+    fn insert(&self, session: &Session) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let info = SessionInfo {
+            id,
+            peer_id: session.peer_id,
+            direction: session.direction,
+            relayed: session.relayed,
+            host: session.host.clone(),
+            port: session.port,
+            opened_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        };
+        self.sessions.lock().unwrap().insert(id, info);
+        id
+    }
+}
+
+// Removes a session from the active ones however it ends, also when its task is dropped
+struct Registration<'a> {
+    active: &'a ActiveSessions,
+    id: u64,
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        self.active.sessions.lock().unwrap().remove(&self.id);
+    }
+}
+
+// A proxied connection between a local tcp stream and a peer
+struct Session {
+    active: ActiveSessions,
+    peer_id: PeerId,
+    direction: Direction,
+    // proxied over a relayed connection, if known
+    relayed: Option<bool>,
+    host: String,
+    port: u16,
+}
+
+impl Session {
+    async fn proxy(self, local_stream: TcpStream, remote_stream: Stream) {
+        let _registration = Registration {
+            active: &self.active,
+            id: self.active.insert(&self),
+        };
+        let _ = self.active.events.send(Event::SessionOpened {
+            peer_id: self.peer_id,
+            direction: self.direction,
+            relayed: self.relayed,
+            host: self.host.clone(),
+            port: self.port,
+        });
+        match proxy_streams(local_stream, remote_stream).await {
+            Ok((local_to_remote, remote_to_local)) => {
+                let _ = self.active.events.send(Event::SessionClosed {
+                    peer_id: self.peer_id,
+                    direction: self.direction,
+                    relayed: self.relayed,
+                    host: self.host,
+                    port: self.port,
+                    local_to_remote,
+                    remote_to_local,
+                });
+            }
+            Err(error) => self.failed(error),
+        }
+    }
+
+    fn failed(&self, error: impl fmt::Display) {
+        let _ = self.active.events.send(Event::SessionFailed {
+            peer_id: self.peer_id,
+            direction: self.direction,
+            host: self.host.clone(),
+            port: self.port,
+            error: error.to_string(),
+        });
+    }
+}
+
+// Copy between a local tcp stream and a remote libp2p stream until both sides are done
+async fn proxy_streams(mut local_stream: TcpStream, remote_stream: Stream) -> io::Result<(u64, u64)> {
+    // Convert remote_stream to imple tokio AsyncRead and AsyncWrite
+    // TODO: redeem this atrocity...
+    let (remote_read, remote_write) = remote_stream.split();
+    let remote_read = remote_read.compat();
+    let remote_write = remote_write.compat_write();
+    let mut remote_stream = TokioReadWrite {
+        reader: remote_read,
+        writer: remote_write,
+    };
+
+    // Copy between remote and local
+    let (local_to_remote, remote_to_local) =
+        copy_bidirectional(&mut local_stream, &mut remote_stream)
+            .await
+            .inspect_err(|error| tracing::error!(?error, "proxy error"))?;
+    tracing::info!(%local_to_remote, %remote_to_local, "proxing done successfully");
+    Ok((local_to_remote, remote_to_local))
+}
+
+#[pin_project]
+struct TokioReadWrite<R, W> {
+    #[pin]
+    reader: R,
+    #[pin]
+    writer: W,
+}
+
+impl<R: tokio::io::AsyncRead, W: tokio::io::AsyncWrite> tokio::io::AsyncRead
+    for TokioReadWrite<R, W>
+{
+    fn poll_read(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> std::task::Poll<io::Result<()>> {
+        let mut this = self.project();
+        this.reader.as_mut().poll_read(cx, buf)
+    }
+}
+
+impl<R: tokio::io::AsyncRead, W: tokio::io::AsyncWrite> tokio::io::AsyncWrite
+    for TokioReadWrite<R, W>
+{
+    fn poll_write(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<Result<usize, io::Error>> {
+        let mut this = self.project();
+        this.writer.as_mut().poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), io::Error>> {
+        let this = self.project();
+        this.writer.poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Result<(), io::Error>> {
+        let mut this = self.project();
+        this.writer.as_mut().poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::AsyncWriteExt;
+    use libp2p::{
+        core::{transport::MemoryTransport, upgrade, Transport},
+        noise,
+        swarm::SwarmEvent,
+        yamux, Swarm,
+    };
+
+    const LOCALHOST: &str = "127.0.0.1";
+
+    // A node speaking only libp2p_stream over the in-memory transport
+    fn new_swarm() -> Swarm<stream::Behaviour> {
+        libp2p::SwarmBuilder::with_new_identity()
+            .with_tokio()
+            .with_other_transport(|key| {
+                MemoryTransport::default()
+                    .upgrade(upgrade::Version::V1)
+                    .authenticate(noise::Config::new(key).unwrap())
+                    .multiplex(yamux::Config::default())
+            })
+            .unwrap()
+            .with_behaviour(|_| stream::Behaviour::new())
+            .unwrap()
+            .with_swarm_config(|config| config.with_idle_connection_timeout(Duration::from_secs(60)))
+            .build()
+    }
+
+    // Controls of a consumer connected to a provider, and the provider's peer id. Both swarms
+    // are driven in the background.
+    async fn connected_pair() -> (stream::Control, stream::Control, PeerId) {
+        let mut provider = new_swarm();
+        let mut consumer = new_swarm();
+        provider.listen_on("/memory/0".parse().unwrap()).unwrap();
+        let address = loop {
+            if let SwarmEvent::NewListenAddr { address, .. } = provider.select_next_some().await {
+                break address;
+            }
+        };
+        let provider_id = *provider.local_peer_id();
+        let provider_control = provider.behaviour().new_control();
+        let consumer_control = consumer.behaviour().new_control();
+        tokio::spawn(async move {
+            loop {
+                provider.select_next_some().await;
+            }
+        });
+        consumer.dial(address).unwrap();
+        loop {
+            if let SwarmEvent::ConnectionEstablished { .. } = consumer.select_next_some().await {
+                break;
+            }
+        }
+        tokio::spawn(async move {
+            loop {
+                consumer.select_next_some().await;
+            }
+        });
+        (consumer_control, provider_control, provider_id)
+    }
+
+    // Serve proxy streams like a provider supporting only `protocols`, returning its sessions
+    fn serve(
+        mut control: stream::Control,
+        provided_services: ProvidedServices,
+        protocols: &[(StreamProtocol, ProxyVersion)],
+    ) -> ActiveSessions {
+        let mut incoming = accept_streams(&mut control, protocols);
+        let (events, _) = broadcast::channel(16);
+        let active = ActiveSessions::new(events);
+        let sessions = active.clone();
+        tokio::spawn(async move {
+            while let Some((peer_id, stream, version)) = incoming.next().await {
+                tokio::spawn(handle_inbound(
+                    provided_services.clone(),
+                    peer_id,
+                    stream,
+                    version,
+                    active.clone(),
+                    Some(false),
+                ));
+            }
+        });
+        sessions
+    }
+
+    // Port of a local tcp service echoing what it receives
+    async fn echo_service() -> u16 {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let (mut reader, mut writer) = stream.split();
+                    let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                });
+            }
+        });
+        port
+    }
+
+    // Port nothing listens on
+    async fn closed_port() -> u16 {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).await.unwrap();
+        listener.local_addr().unwrap().port()
+    }
+
+    fn provided(port: u16) -> ProvidedServices {
+        Arc::new(Mutex::new(HashSet::from([(LOCALHOST.to_string(), port)])))
+    }
+
+    async fn assert_echoes(mut stream: Stream) -> Stream {
+        stream.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        stream
+    }
+
+    #[tokio::test]
+    async fn newest_version_is_negotiated() {
+        let (mut consumer, provider, provider_id) = connected_pair().await;
+        serve(provider, provided(echo_service().await), &PROXY_PROTOCOLS);
+        let (_, version) = open_stream(&mut consumer, provider_id, &PROXY_PROTOCOLS)
+            .await
+            .unwrap();
+        assert_eq!(version, ProxyVersion::V2);
+    }
+
+    #[tokio::test]
+    async fn v2_consumer_with_unversioned_provider() {
+        let (mut consumer, provider, provider_id) = connected_pair().await;
+        let port = echo_service().await;
+        serve(provider, provided(port), &PROXY_PROTOCOLS[2..]);
+        let stream = connect(&mut consumer, provider_id, LOCALHOST.into(), port)
+            .await
+            .unwrap();
+        assert_echoes(stream).await;
+    }
+
+    #[tokio::test]
+    async fn v2_consumer_with_v1_provider() {
+        let (mut consumer, provider, provider_id) = connected_pair().await;
+        let port = echo_service().await;
+        serve(provider, provided(port), &PROXY_PROTOCOLS[1..2]);
+        let stream = connect(&mut consumer, provider_id, LOCALHOST.into(), port)
+            .await
+            .unwrap();
+        assert_echoes(stream).await;
+    }
+
+    #[tokio::test]
+    async fn v1_consumer_with_v2_provider() {
+        let (mut consumer, provider, provider_id) = connected_pair().await;
+        let port = echo_service().await;
+        serve(provider, provided(port), &PROXY_PROTOCOLS);
+        let stream = connect_with(
+            &mut consumer,
+            provider_id,
+            LOCALHOST.into(),
+            port,
+            &PROXY_PROTOCOLS[1..2],
+        )
+        .await
+        .unwrap();
+        assert_echoes(stream).await;
+    }
+
+    #[tokio::test]
+    async fn unversioned_consumer_with_v2_provider() {
+        let (mut consumer, provider, provider_id) = connected_pair().await;
+        let port = echo_service().await;
+        serve(provider, provided(port), &PROXY_PROTOCOLS);
+        let stream = connect_with(
+            &mut consumer,
+            provider_id,
+            LOCALHOST.into(),
+            port,
+            &PROXY_PROTOCOLS[2..],
+        )
+        .await
+        .unwrap();
+        assert_echoes(stream).await;
+    }
+
+    #[tokio::test]
+    async fn no_common_version() {
+        let (mut consumer, provider, provider_id) = connected_pair().await;
+        serve(provider, provided(echo_service().await), &PROXY_PROTOCOLS[..1]);
+        let result = connect_with(
+            &mut consumer,
+            provider_id,
+            LOCALHOST.into(),
+            0,
+            &PROXY_PROTOCOLS[1..],
+        )
+        .await;
+        assert!(matches!(result, Err(ConnectError::UnsupportedProtocol)));
+    }
+
+    #[tokio::test]
+    async fn sessions_are_listed_while_open() {
+        let (mut consumer, provider, provider_id) = connected_pair().await;
+        let port = echo_service().await;
+        let active = serve(provider, provided(port), &PROXY_PROTOCOLS);
+        let stream = connect(&mut consumer, provider_id, LOCALHOST.into(), port)
+            .await
+            .unwrap();
+        let mut stream = assert_echoes(stream).await;
+        let sessions = active.list();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(sessions[0].direction, Direction::Inbound);
+        assert_eq!((sessions[0].host.as_str(), sessions[0].port), (LOCALHOST, port));
+        stream.close().await.unwrap();
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !active.list().is_empty() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("closed session should be removed");
+    }
+
+    #[tokio::test]
+    async fn not_allowed() {
+        let (mut consumer, provider, provider_id) = connected_pair().await;
+        let port = echo_service().await;
+        serve(provider, provided(port), &PROXY_PROTOCOLS);
+        let result = connect(&mut consumer, provider_id, LOCALHOST.into(), port + 1).await;
+        assert!(matches!(result, Err(ConnectError::NotAllowed)));
+    }
+
+    #[tokio::test]
+    async fn not_allowed_v1() {
+        let (mut consumer, provider, provider_id) = connected_pair().await;
+        let port = echo_service().await;
+        serve(provider, provided(port), &PROXY_PROTOCOLS[1..2]);
+        let result = connect(&mut consumer, provider_id, LOCALHOST.into(), port + 1).await;
+        assert!(matches!(result, Err(ConnectError::NotAllowed)));
+    }
+
+    #[tokio::test]
+    async fn connect_failed() {
+        let (mut consumer, provider, provider_id) = connected_pair().await;
+        let port = closed_port().await;
+        serve(provider, provided(port), &PROXY_PROTOCOLS);
+        let result = connect(&mut consumer, provider_id, LOCALHOST.into(), port).await;
+        assert!(matches!(result, Err(ConnectError::ConnectFailed)));
+    }
+}
@@ -0,0 +1,255 @@
+//! Bookkeeping of known relays, so that circuit reservations are kept on a bounded number of
+//! the best of them and failing relays are replaced.
+
+use libp2p::{core::transport::ListenerId, PeerId};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::data::{RelayState, RelayStatus};
+
+// first retry delay of a failed relay, doubled on every further failure
+const RETRY_BACKOFF: Duration = Duration::from_secs(30);
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(30 * 60);
+
+pub(crate) struct RelayManager {
+    max_reservations: usize,
+    relays: HashMap<PeerId, Relay>,
+}
+
+#[derive(Default)]
+struct Relay {
+    // dialing the relay, we listen through it once connected
+    dialing: bool,
+    // circuit listener requesting or holding a reservation
+    listener: Option<ListenerId>,
+    reserved: bool,
+    accepted: u32,
+    failed: u32,
+    retry_at: Option<Instant>,
+}
+
+impl Relay {
+    fn is_active(&self) -> bool {
+        self.dialing || self.listener.is_some()
+    }
+
+    // Lower is better: relays accepting our reservations, then the closest ones
+    fn rank(&self, rtt: Option<Duration>) -> (u32, Duration) {
+        (
+            self.failed.saturating_sub(self.accepted),
+            rtt.unwrap_or(Duration::MAX),
+        )
+    }
+
+    fn stop(&mut self) -> Option<ListenerId> {
+        self.dialing = false;
+        self.reserved = false;
+        self.listener.take()
+    }
+
+    fn fail(&mut self) -> Option<ListenerId> {
+        self.dialing = false;
+        self.reserved = false;
+        self.failed += 1;
+        let backoff = RETRY_BACKOFF
+            .saturating_mul(2u32.saturating_pow(self.failed - 1))
+            .min(MAX_RETRY_BACKOFF);
+        self.retry_at = Some(Instant::now() + backoff);
+        self.listener.take()
+    }
+}
+
+impl RelayManager {
+    pub(crate) fn new(max_reservations: usize) -> Self {
+        Self {
+            max_reservations,
+            relays: Default::default(),
+        }
+    }
+
+    /// Keep reservations on at most `max_reservations` relays from now on, returns the
+    /// listeners of the worst relays to remove if more are in use.
+    pub(crate) fn set_max_reservations(
+        &mut self,
+        max_reservations: usize,
+        rtt: impl Fn(&PeerId) -> Option<Duration>,
+    ) -> Vec<ListenerId> {
+        self.max_reservations = max_reservations;
+        let mut active = self
+            .relays
+            .iter()
+            .filter(|(_, r)| r.is_active())
+            .map(|(peer_id, r)| (!r.reserved, r.rank(rtt(peer_id)), *peer_id))
+            .collect::<Vec<_>>();
+        active.sort();
+        active
+            .into_iter()
+            .skip(max_reservations)
+            .filter_map(|(_, _, peer_id)| {
+                tracing::info!(relay = %peer_id, "stop using relay");
+                self.relays.get_mut(&peer_id).and_then(Relay::stop)
+            })
+            .collect()
+    }
+
+    pub(crate) fn contains(&self, relay: &PeerId) -> bool {
+        self.relays.contains_key(relay)
+    }
+
+    pub(crate) fn add(&mut self, relay: PeerId) {
+        self.relays.entry(relay).or_default();
+    }
+
+    pub(crate) fn is_dialing(&self, relay: &PeerId) -> bool {
+        self.relays.get(relay).is_some_and(|r| r.dialing)
+    }
+
+    pub(crate) fn dialing(&mut self, relay: PeerId) {
+        self.relays.entry(relay).or_default().dialing = true;
+    }
+
+    pub(crate) fn listening(&mut self, relay: PeerId, listener_id: ListenerId) {
+        let relay = self.relays.entry(relay).or_default();
+        relay.dialing = false;
+        relay.listener = Some(listener_id);
+    }
+
+    pub(crate) fn accepted(&mut self, relay: PeerId) {
+        let relay = self.relays.entry(relay).or_default();
+        relay.reserved = true;
+        relay.accepted += 1;
+        relay.retry_at = None;
+    }
+
+    /// Dialing or listening through `relay` failed.
+    pub(crate) fn failed(&mut self, relay: &PeerId) {
+        if let Some(relay) = self.relays.get_mut(relay) {
+            relay.fail();
+        }
+    }
+
+    /// The last connection to `relay` closed, returns the listener to remove if we were
+    /// listening through it.
+    pub(crate) fn disconnected(&mut self, relay: &PeerId) -> Option<ListenerId> {
+        self.relays
+            .get_mut(relay)
+            .filter(|r| r.listener.is_some())
+            .and_then(Relay::fail)
+    }
+
+    /// Stop using all relays, returns the listeners to remove.
+    pub(crate) fn release(&mut self) -> Vec<ListenerId> {
+        self.relays.values_mut().filter_map(Relay::stop).collect()
+    }
+
+    /// A circuit listener closed, e.g. because the reservation was denied. Returns its relay.
+    pub(crate) fn listener_closed(&mut self, listener_id: ListenerId) -> Option<PeerId> {
+        let (peer_id, relay) = self
+            .relays
+            .iter_mut()
+            .find(|(_, r)| r.listener == Some(listener_id))?;
+        relay.fail();
+        Some(*peer_id)
+    }
+
+    /// Relays to reserve on to fill the free slots, best first.
+    pub(crate) fn select(&self, rtt: impl Fn(&PeerId) -> Option<Duration>) -> Vec<PeerId> {
+        let active = self.relays.values().filter(|r| r.is_active()).count();
+        let now = Instant::now();
+        let mut candidates = self
+            .relays
+            .iter()
+            .filter(|(_, r)| !r.is_active() && r.retry_at.is_none_or(|at| at <= now))
+            .map(|(peer_id, r)| (r.rank(rtt(peer_id)), *peer_id))
+            .collect::<Vec<_>>();
+        candidates.sort();
+        candidates
+            .into_iter()
+            .take(self.max_reservations.saturating_sub(active))
+            .map(|(_, peer_id)| peer_id)
+            .collect()
+    }
+
+    /// When the next backed off relay may be retried.
+    pub(crate) fn next_retry(&self) -> Option<Instant> {
+        let now = Instant::now();
+        self.relays
+            .values()
+            .filter(|r| !r.is_active())
+            .filter_map(|r| r.retry_at)
+            .filter(|at| *at > now)
+            .min()
+    }
+
+    pub(crate) fn status(&self, rtt: impl Fn(&PeerId) -> Option<Duration>) -> Vec<RelayStatus> {
+        self.relays
+            .iter()
+            .map(|(peer_id, r)| RelayStatus {
+                peer_id: *peer_id,
+                state: if r.reserved {
+                    RelayState::Reserved
+                } else if r.is_active() {
+                    RelayState::Reserving
+                } else {
+                    RelayState::Candidate
+                },
+                rtt_ms: rtt(peer_id).map(|rtt| rtt.as_secs_f64() * 1000.0),
+                accepted: r.accepted,
+                failed: r.failed,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reserved(relays: &mut RelayManager) -> (PeerId, ListenerId) {
+        let relay = PeerId::random();
+        let listener_id = ListenerId::next();
+        relays.add(relay);
+        relays.listening(relay, listener_id);
+        relays.accepted(relay);
+        (relay, listener_id)
+    }
+
+    #[test]
+    fn lowering_max_reservations_releases_the_slowest() {
+        let mut relays = RelayManager::new(3);
+        let (fast, _) = reserved(&mut relays);
+        let (slow, slow_listener) = reserved(&mut relays);
+        let (unknown, unknown_listener) = reserved(&mut relays);
+        let rtt = |relay: &PeerId| {
+            if *relay == fast {
+                Some(Duration::from_millis(10))
+            } else if *relay == slow {
+                Some(Duration::from_millis(100))
+            } else {
+                None
+            }
+        };
+        let released = relays.set_max_reservations(1, rtt);
+        assert_eq!(released.len(), 2);
+        assert!(released.contains(&slow_listener) && released.contains(&unknown_listener));
+        let status = relays.status(rtt);
+        let state = |relay| status.iter().find(|s| s.peer_id == relay).unwrap().state;
+        assert_eq!(state(fast), RelayState::Reserved);
+        assert_eq!(state(slow), RelayState::Candidate);
+        assert_eq!(state(unknown), RelayState::Candidate);
+        assert!(relays.select(rtt).is_empty());
+    }
+
+    #[test]
+    fn failed_relays_are_retried_after_backoff() {
+        let mut relays = RelayManager::new(1);
+        let relay = PeerId::random();
+        relays.add(relay);
+        assert_eq!(relays.next_retry(), None);
+        relays.dialing(relay);
+        relays.failed(&relay);
+        let retry_at = relays.next_retry().expect("failed relay should be retried");
+        assert!(retry_at > Instant::now() + RETRY_BACKOFF - Duration::from_secs(1));
+        assert!(relays.select(|_| None).is_empty());
+    }
+}
@@ -0,0 +1,398 @@
+//! Limits of the relay server run by relay nodes, and accounting of the circuits relayed
+//! through them.
+
+use libp2p::{relay, Multiaddr, PeerId};
+use prometheus_client::{
+    encoding::EncodeLabelSet,
+    metrics::{counter::Counter, family::Family, gauge::Gauge},
+    registry::Registry,
+};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::num::NonZeroU32;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// window of `RelayConfig::rate_per_minute`
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Limits and policies of the relay server, see [`crate::NetworkConfig`].
+///
+/// `rate_per_minute` and `allowed_peers` can be changed on a running relay, the other limits
+/// only apply to a new one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RelayConfig {
+    pub max_reservations: usize,
+    pub max_reservations_per_peer: usize,
+    pub reservation_duration: Duration,
+    pub max_circuits: usize,
+    pub max_circuits_per_peer: usize,
+    pub max_circuit_duration: Duration,
+    pub max_circuit_bytes: u64,
+    /// Reservations and circuits each peer may request per minute, on top of the libp2p
+    /// default rate limits
+    pub rate_per_minute: Option<NonZeroU32>,
+    /// Peers allowed to reserve or relay through us, anyone if empty
+    pub allowed_peers: HashSet<PeerId>,
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        let config = relay::Config::default();
+        Self {
+            max_reservations: config.max_reservations,
+            max_reservations_per_peer: config.max_reservations_per_peer,
+            reservation_duration: config.reservation_duration,
+            max_circuits: config.max_circuits,
+            max_circuits_per_peer: config.max_circuits_per_peer,
+            max_circuit_duration: config.max_circuit_duration,
+            max_circuit_bytes: config.max_circuit_bytes,
+            rate_per_minute: None,
+            allowed_peers: Default::default(),
+        }
+    }
+}
+
+impl RelayConfig {
+    // Whether the limits fixed when the relay is created are the same
+    pub(crate) fn same_limits(&self, other: &RelayConfig) -> bool {
+        let limits = |config: &RelayConfig| RelayConfig {
+            rate_per_minute: None,
+            allowed_peers: Default::default(),
+            ..config.clone()
+        };
+        limits(self) == limits(other)
+    }
+
+    // Config of the relay behaviour, with the rate limit and allow list enforced by `policy`
+    pub(crate) fn relay_config(&self, policy: &RelayPolicy) -> relay::Config {
+        let mut relay_config = relay::Config {
+            max_reservations: self.max_reservations,
+            max_reservations_per_peer: self.max_reservations_per_peer,
+            reservation_duration: self.reservation_duration,
+            max_circuits: self.max_circuits,
+            max_circuits_per_peer: self.max_circuits_per_peer,
+            max_circuit_duration: self.max_circuit_duration,
+            max_circuit_bytes: self.max_circuit_bytes,
+            ..Default::default()
+        };
+        policy.set(self);
+        relay_config
+            .reservation_rate_limiters
+            .push(Box::new(policy.limiter(Request::Reservation)));
+        relay_config
+            .circuit_src_rate_limiters
+            .push(Box::new(policy.limiter(Request::Circuit)));
+        relay_config
+    }
+}
+
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+enum Request {
+    Reservation,
+    Circuit,
+}
+
+// The reloadable part of a `RelayConfig`, shared with the rate limiters of the relay
+#[derive(Clone, Default)]
+pub(crate) struct RelayPolicy(Arc<Mutex<Policy>>);
+
+#[derive(Default)]
+struct Policy {
+    rate_per_minute: Option<NonZeroU32>,
+    allowed_peers: HashSet<PeerId>,
+    // times of the requests of each peer in the last minute, oldest first
+    requests: HashMap<(Request, PeerId), VecDeque<Instant>>,
+    // when peers without recent requests were last forgotten
+    swept: Option<Instant>,
+}
+
+impl RelayPolicy {
+    pub(crate) fn set(&self, config: &RelayConfig) {
+        let mut policy = self.0.lock().unwrap();
+        policy.rate_per_minute = config.rate_per_minute;
+        policy.allowed_peers = config.allowed_peers.clone();
+        if policy.rate_per_minute.is_none() {
+            policy.requests.clear();
+        }
+    }
+
+    fn limiter(&self, request: Request) -> impl FnMut(PeerId, &Multiaddr, Instant) -> bool + Send {
+        let policy = self.0.clone();
+        move |peer_id, _, now| policy.lock().unwrap().allow(request, peer_id, now)
+    }
+}
+
+impl Policy {
+    fn allow(&mut self, request: Request, peer_id: PeerId, now: Instant) -> bool {
+        // the destination of a circuit needs a reservation, so both ends must be allowed
+        if !self.allowed_peers.is_empty() && !self.allowed_peers.contains(&peer_id) {
+            return false;
+        }
+        let Some(limit) = self.rate_per_minute else {
+            return true;
+        };
+        // forget peers without requests in the window, at most once per window
+        if self
+            .swept
+            .is_none_or(|at| now.duration_since(at) >= RATE_WINDOW)
+        {
+            self.requests.retain(|_, times| {
+                times
+                    .back()
+                    .is_some_and(|at| now.duration_since(*at) < RATE_WINDOW)
+            });
+            self.swept = Some(now);
+        }
+        let times = self.requests.entry((request, peer_id)).or_default();
+        while times
+            .front()
+            .is_some_and(|at| now.duration_since(*at) >= RATE_WINDOW)
+        {
+            times.pop_front();
+        }
+        if times.len() >= limit.get() as usize {
+            return false;
+        }
+        times.push_back(now);
+        true
+    }
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct CircuitLabels {
+    src: String,
+    dst: String,
+}
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq, EncodeLabelSet)]
+struct PeerLabels {
+    peer: String,
+}
+
+// Per circuit accounting, exported as `preers_relay_*` metrics. Only open circuits and held
+// reservations are labelled by peer, so that the series don't grow with every peer ever seen.
+pub(crate) struct RelayMetrics {
+    circuits: Family<CircuitLabels, Gauge>,
+    circuits_accepted: Counter,
+    circuits_denied: Counter,
+    circuit_seconds: Counter<f64>,
+    reservations: Family<PeerLabels, Gauge>,
+    reservations_denied: Counter,
+    // reservations held by each peer, at most one per connection
+    peer_reservations: HashMap<PeerId, u32>,
+    // start of the open circuits of each (src, dst) pair, oldest first
+    open_circuits: HashMap<(PeerId, PeerId), Vec<Instant>>,
+}
+
+impl RelayMetrics {
+    pub(crate) fn new(registry: &mut Registry) -> Self {
+        let registry = registry.sub_registry_with_prefix("relay");
+        let metrics = Self {
+            circuits: Default::default(),
+            circuits_accepted: Default::default(),
+            circuits_denied: Default::default(),
+            circuit_seconds: Default::default(),
+            reservations: Default::default(),
+            reservations_denied: Default::default(),
+            peer_reservations: Default::default(),
+            open_circuits: Default::default(),
+        };
+        registry.register(
+            "circuits",
+            "Circuits currently relayed",
+            metrics.circuits.clone(),
+        );
+        registry.register(
+            "circuits_accepted",
+            "Circuits accepted",
+            metrics.circuits_accepted.clone(),
+        );
+        registry.register(
+            "circuits_denied",
+            "Circuits denied",
+            metrics.circuits_denied.clone(),
+        );
+        registry.register(
+            "circuit_seconds",
+            "Time spent relaying circuits",
+            metrics.circuit_seconds.clone(),
+        );
+        registry.register(
+            "reservations",
+            "Reservations currently held",
+            metrics.reservations.clone(),
+        );
+        registry.register(
+            "reservations_denied",
+            "Reservations denied",
+            metrics.reservations_denied.clone(),
+        );
+        metrics
+    }
+
+    pub(crate) fn record(&mut self, event: &relay::Event) {
+        match event {
+            relay::Event::ReservationReqAccepted {
+                src_peer_id,
+                renewed: false,
+            } => {
+                let held = self.peer_reservations.get(src_peer_id).copied().unwrap_or(0);
+                self.set_reservations(src_peer_id, held + 1);
+            }
+            relay::Event::ReservationTimedOut { src_peer_id } => {
+                let held = self.peer_reservations.get(src_peer_id).copied().unwrap_or(0);
+                self.set_reservations(src_peer_id, held.saturating_sub(1));
+            }
+            relay::Event::ReservationReqDenied { .. } => {
+                self.reservations_denied.inc();
+            }
+            relay::Event::CircuitReqAccepted {
+                src_peer_id,
+                dst_peer_id,
+            } => {
+                self.circuits_accepted.inc();
+                let started = self
+                    .open_circuits
+                    .entry((*src_peer_id, *dst_peer_id))
+                    .or_default();
+                started.push(Instant::now());
+                self.circuits
+                    .get_or_create(&circuit(src_peer_id, dst_peer_id))
+                    .set(started.len() as i64);
+            }
+            relay::Event::CircuitReqDenied { .. } => {
+                self.circuits_denied.inc();
+            }
+            relay::Event::CircuitClosed {
+                src_peer_id,
+                dst_peer_id,
+                ..
+            } => {
+                let key = (*src_peer_id, *dst_peer_id);
+                let labels = circuit(src_peer_id, dst_peer_id);
+                let Some(started) = self.open_circuits.get_mut(&key) else {
+                    return;
+                };
+                if !started.is_empty() {
+                    let elapsed = started.remove(0).elapsed();
+                    self.circuit_seconds.inc_by(elapsed.as_secs_f64());
+                }
+                if started.is_empty() {
+                    self.open_circuits.remove(&key);
+                    self.circuits.remove(&labels);
+                } else {
+                    self.circuits.get_or_create(&labels).set(started.len() as i64);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// A connection to `peer_id` closed, `remaining` are still established.
+    ///
+    /// The relay drops the reservation of a closed connection without an event, and keeps at
+    /// most one per connection, so a peer holds no more reservations than it has connections.
+    pub(crate) fn connection_closed(&mut self, peer_id: &PeerId, remaining: u32) {
+        if let Some(held) = self.peer_reservations.get(peer_id).copied() {
+            self.set_reservations(peer_id, held.min(remaining));
+        }
+    }
+
+    // Peers without reservations are removed, so that the gauge doesn't grow with every peer
+    // ever seen
+    fn set_reservations(&mut self, peer_id: &PeerId, held: u32) {
+        if held == 0 {
+            self.peer_reservations.remove(peer_id);
+            self.reservations.remove(&peer(peer_id));
+        } else {
+            self.peer_reservations.insert(*peer_id, held);
+            self.reservations.get_or_create(&peer(peer_id)).set(held.into());
+        }
+    }
+}
+
+fn peer(peer_id: &PeerId) -> PeerLabels {
+    PeerLabels {
+        peer: peer_id.to_base58(),
+    }
+}
+
+fn circuit(src_peer_id: &PeerId, dst_peer_id: &PeerId) -> CircuitLabels {
+    CircuitLabels {
+        src: src_peer_id.to_base58(),
+        dst: dst_peer_id.to_base58(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_limit_forgets_idle_peers() {
+        let mut policy = Policy {
+            rate_per_minute: NonZeroU32::new(1),
+            ..Default::default()
+        };
+        let start = Instant::now();
+        let (busy, idle) = (PeerId::random(), PeerId::random());
+        assert!(policy.allow(Request::Reservation, idle, start));
+        assert!(policy.allow(Request::Reservation, busy, start));
+        assert!(!policy.allow(Request::Reservation, busy, start + Duration::from_secs(1)));
+        let later = start + RATE_WINDOW;
+        assert!(policy.allow(Request::Reservation, busy, later));
+        assert_eq!(policy.requests.len(), 1);
+        assert!(policy.requests.contains_key(&(Request::Reservation, busy)));
+    }
+
+    #[test]
+    fn reservations_end_with_their_connections() {
+        let mut metrics = RelayMetrics::new(&mut Registry::default());
+        let peer_id = PeerId::random();
+        for _ in 0..2 {
+            metrics.record(&relay::Event::ReservationReqAccepted {
+                src_peer_id: peer_id,
+                renewed: false,
+            });
+        }
+        metrics.connection_closed(&peer_id, 1);
+        assert_eq!(metrics.peer_reservations.get(&peer_id), Some(&1));
+        metrics.connection_closed(&peer_id, 0);
+        assert!(metrics.peer_reservations.is_empty());
+    }
+
+    #[test]
+    fn closed_circuits_leave_no_series() {
+        let mut registry = Registry::default();
+        let mut metrics = RelayMetrics::new(&mut registry);
+        let (src_peer_id, dst_peer_id) = (PeerId::random(), PeerId::random());
+        let encoded = |registry: &Registry| {
+            let mut text = String::new();
+            prometheus_client::encoding::text::encode(&mut text, registry).unwrap();
+            text
+        };
+        for _ in 0..2 {
+            metrics.record(&relay::Event::CircuitReqAccepted {
+                src_peer_id,
+                dst_peer_id,
+            });
+        }
+        metrics.record(&relay::Event::CircuitReqDenied {
+            src_peer_id,
+            dst_peer_id,
+        });
+        assert!(encoded(&registry).contains(&src_peer_id.to_base58()));
+        for _ in 0..2 {
+            metrics.record(&relay::Event::CircuitClosed {
+                src_peer_id,
+                dst_peer_id,
+                error: None,
+            });
+        }
+        let text = encoded(&registry);
+        assert!(!text.contains(&src_peer_id.to_base58()));
+        assert!(!text.contains(&dst_peer_id.to_base58()));
+        assert!(text.contains("relay_circuits_accepted_total 2"));
+        assert!(metrics.open_circuits.is_empty());
+    }
+}
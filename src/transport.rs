@@ -0,0 +1,218 @@
+//! Transports a node dials and listens with, and the addresses it listens on.
+
+use futures::future::Either;
+use libp2p::{
+    core::{
+        multiaddr::Protocol,
+        muxing::StreamMuxerBox,
+        transport::{upgrade::Version, Boxed, OptionalTransport},
+    },
+    dns,
+    identity::Keypair,
+    noise,
+    pnet::{PnetConfig, PreSharedKey},
+    quic, tcp, websocket, yamux, Multiaddr, PeerId, Transport,
+};
+use std::error::Error;
+use std::fmt;
+
+/// Transports and listen addresses of a node, see [`crate::NetworkConfig`].
+#[derive(Clone, Debug)]
+pub struct TransportConfig {
+    pub tcp: bool,
+    /// Ignored in a private network, where QUIC is not supported
+    pub quic: bool,
+    pub ipv6: bool,
+    /// Dial and listen over WebSocket, e.g. to get through firewalls only letting HTTPS out
+    pub websocket: bool,
+    pub tcp_port: u16,
+    pub udp_port: u16,
+    pub ws_port: u16,
+    /// Certificate of WebSocket listeners, `/tls/ws` addresses can't be listened on without one
+    pub ws_tls: Option<TlsCertificate>,
+    /// Addresses to listen on, any address of the enabled transports and ports if empty
+    pub listen: Vec<Multiaddr>,
+    /// Only connect to peers holding the same pre-shared key
+    pub psk: Option<PreSharedKey>,
+}
+
+impl Default for TransportConfig {
+    fn default() -> Self {
+        Self {
+            tcp: true,
+            quic: true,
+            ipv6: true,
+            websocket: false,
+            tcp_port: 0,
+            udp_port: 0,
+            ws_port: 0,
+            ws_tls: None,
+            listen: Default::default(),
+            psk: None,
+        }
+    }
+}
+
+impl TransportConfig {
+    pub(crate) fn is_quic(&self) -> bool {
+        self.quic && self.psk.is_none()
+    }
+
+    /// Whether `address` can be dialed or listened on with the enabled transports.
+    pub(crate) fn check(&self, address: &Multiaddr) -> Result<(), String> {
+        // relayed addresses are reached over their relay, e.g. TCP in `/tcp/1/p2p/relay/p2p-circuit`
+        let protocols = address
+            .iter()
+            .take_while(|protocol| !matches!(protocol, Protocol::P2pCircuit))
+            .collect::<Vec<_>>();
+        let is = |f: fn(&Protocol) -> bool| protocols.iter().any(f);
+        // no WebTransport server is available outside of browsers, QUIC is the closest
+        if is(|p| matches!(p, Protocol::WebTransport)) {
+            return Err(format!(
+                "WebTransport is not supported, use a /quic-v1 or /wss address instead of {address}"
+            ));
+        }
+        if is(|p| matches!(p, Protocol::QuicV1)) {
+            if self.psk.is_some() {
+                return Err(format!(
+                    "QUIC is not supported in a private network, use a TCP address instead of {address}"
+                ));
+            }
+            if !self.quic {
+                return Err(format!("QUIC is disabled, can't use {address}"));
+            }
+        } else if is(|p| matches!(p, Protocol::Ws(_) | Protocol::Wss(_))) {
+            if !self.websocket {
+                return Err(format!("WebSocket is disabled, can't use {address}"));
+            }
+        } else if is(|p| matches!(p, Protocol::Tcp(_))) && !self.tcp {
+            return Err(format!("TCP is disabled, can't use {address}"));
+        }
+        Ok(())
+    }
+
+    // Addresses given with `listen`, or any address of every enabled transport
+    pub(crate) fn listen_addrs(&self) -> Result<Vec<Multiaddr>, Box<dyn Error>> {
+        if !self.listen.is_empty() {
+            for address in self.listen.iter() {
+                self.check(address)?;
+                let is_tls = address
+                    .iter()
+                    .any(|p| matches!(p, Protocol::Tls | Protocol::Wss(_)));
+                if is_tls && self.ws_tls.is_none() {
+                    return Err(format!("listening on {address} needs a TLS certificate").into());
+                }
+            }
+            return Ok(self.listen.clone());
+        }
+        let mut hosts = vec!["/ip4/0.0.0.0"];
+        if self.ipv6 {
+            hosts.push("/ip6/::");
+        }
+        let mut addrs = Vec::new();
+        for host in hosts {
+            if self.tcp {
+                addrs.push(format!("{host}/tcp/{}", self.tcp_port).parse()?);
+            }
+            if self.is_quic() {
+                addrs.push(format!("{host}/udp/{}/quic-v1", self.udp_port).parse()?);
+            }
+            if self.websocket {
+                let ws = if self.ws_tls.is_some() { "tls/ws" } else { "ws" };
+                addrs.push(format!("{host}/tcp/{}/{ws}", self.ws_port).parse()?);
+            }
+        }
+        if addrs.is_empty() {
+            return Err("no transport enabled, enable TCP, QUIC or WebSocket".into());
+        }
+        Ok(addrs)
+    }
+
+    // WebSocket and TCP, protected by the pre-shared key if any, and QUIC
+    pub(crate) fn build(
+        &self,
+        keypair: &Keypair,
+    ) -> Result<Boxed<(PeerId, StreamMuxerBox)>, Box<dyn Error + Send + Sync>> {
+        let ws = if self.websocket {
+            // resolved by the WebSocket transport, which needs the host name to dial `/wss`
+            let mut ws = websocket::WsConfig::new(dns::tokio::Transport::system(
+                tcp::tokio::Transport::new(tcp::Config::default().nodelay(true)),
+            )?);
+            if let Some(tls) = &self.ws_tls {
+                ws.set_tls_config(tls.config()?);
+            }
+            OptionalTransport::some(ws)
+        } else {
+            OptionalTransport::none()
+        };
+        let tcp = if self.tcp {
+            OptionalTransport::some(tcp::tokio::Transport::new(
+                tcp::Config::default().nodelay(true),
+            ))
+        } else {
+            OptionalTransport::none()
+        };
+        let psk = self.psk;
+        let stream = ws
+            .or_transport(tcp)
+            .and_then(move |socket, _| async move {
+                match psk {
+                    Some(psk) => PnetConfig::new(psk).handshake(socket).await.map(Either::Left),
+                    None => Ok(Either::Right(socket)),
+                }
+            })
+            .upgrade(Version::V1Lazy)
+            .authenticate(noise::Config::new(keypair)?)
+            .multiplex(yamux::Config::default())
+            .map(|(peer_id, muxer), _| (peer_id, StreamMuxerBox::new(muxer)));
+        let quic = if self.is_quic() {
+            OptionalTransport::some(
+                quic::tokio::Transport::new(quic::Config::new(keypair))
+                    .map(|(peer_id, muxer), _| (peer_id, StreamMuxerBox::new(muxer))),
+            )
+        } else {
+            OptionalTransport::none()
+        };
+        Ok(stream
+            .or_transport(quic)
+            .map(|either, _| either.into_inner())
+            .boxed())
+    }
+}
+
+/// TLS certificate chain and private key of WebSocket listeners.
+#[derive(Clone)]
+pub struct TlsCertificate {
+    certs: Vec<websocket::tls::Certificate>,
+    key: websocket::tls::PrivateKey,
+}
+
+impl TlsCertificate {
+    /// Read a PEM encoded certificate chain and private key.
+    pub fn from_pem(mut certs: &[u8], mut key: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let certs = rustls_pemfile::certs(&mut certs)
+            .map(|cert| cert.map(|cert| websocket::tls::Certificate::new(cert.to_vec())))
+            .collect::<Result<Vec<_>, _>>()?;
+        if certs.is_empty() {
+            return Err("no certificate found".into());
+        }
+        let key = rustls_pemfile::private_key(&mut key)?.ok_or("no private key found")?;
+        Ok(Self {
+            certs,
+            key: websocket::tls::PrivateKey::new(key.secret_der().to_vec()),
+        })
+    }
+
+    fn config(&self) -> Result<websocket::tls::Config, websocket::tls::Error> {
+        websocket::tls::Config::new(self.key.clone(), self.certs.clone())
+    }
+}
+
+// keep the private key out of logs
+impl fmt::Debug for TlsCertificate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TlsCertificate")
+            .field("certs", &self.certs.len())
+            .finish_non_exhaustive()
+    }
+}